@@ -0,0 +1,167 @@
+//! The arithmetic and logic unit, and the flag specification every ALU opcode
+//! follows. `a` is reg1, `b` the source operand, and the result goes to reg1.
+//!
+//! ```text
+//! opcode          result               C                                V
+//! ADD             a + b                carry out of bit 15              signed overflow
+//! SUB CMP         a - b                borrow, set when b > a unsigned  signed overflow
+//! ADC             a + b + C            as ADD                           as ADD
+//! SBC             a - b - C            borrow, set when b + C > a       as SUB
+//! INC             a + 1                as ADD                           as ADD
+//! DEC             a - 1                as SUB                           as SUB
+//! MUL             a * b, low word      product does not fit in 16 bits  same as C
+//! MULX            a * b, low word      as MUL                           as MUL
+//! IMUL            a * b, signed        product does not fit in i16      same as C
+//! DIV             a / b, unsigned      0                                0
+//! IDIV            a / b, signed        0                                set for -32768 / -1
+//! MOD             a % b, unsigned      0                                0
+//! IMOD            a % b, signed        0                                0
+//! AND OR XOR NOT  bitwise              0                                0
+//! TEST            a & b                0                                0
+//! SHL             a << b               last bit shifted out             0
+//! SHR             a >> b, logical      last bit shifted out             0
+//! SAR             a >> b, arithmetic   last bit shifted out             0
+//! RCL             C:a rotated left b   last bit rotated out             0
+//! RCR             a:C rotated right b  last bit rotated out             0
+//! ```
+//!
+//! Every ALU opcode sets Z when the result is zero and N to its bit 15. Shifts
+//! by zero clear C, and shifts by 16 or more leave zero, or copies of the sign
+//! bit for SAR. DIV, IDIV, MOD and IMOD by zero fault without touching any flag.
+//! Signed division truncates toward zero and the remainder takes the sign of a.
+//! -32768 / -1 wraps to -32768, so N is set along with V, and -32768 % -1 is 0.
+//! The I flag is never changed by the ALU. CMP and TEST set the flags but leave
+//! reg1 unchanged.
+//!
+//! ADC, SBC and the rotates take C as an input, so chains of them carry across
+//! words: add the low words with ADD then the high words with ADC. RCL and RCR
+//! rotate the 17 bits made of the carry and reg1, so rotating by a multiple of 17
+//! changes nothing, C included. MULX takes two different registers and puts the
+//! high word of the product in reg2, and its Z and N describe the full 32-bit
+//! product.
+
+use crate::{CpuError, Flags, Opcode};
+
+/// A result and the carry and overflow it produced; Z and N follow from the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AluResult {
+    pub(crate) value: u16,
+    pub(crate) carry: bool,
+    pub(crate) overflow: bool,
+}
+
+impl AluResult {
+    fn logic(value: u16) -> Self {
+        AluResult { value, carry: false, overflow: false }
+    }
+
+    pub(crate) fn apply(&self, flags: &mut Flags) {
+        flags.zero = self.value == 0;
+        flags.negative = self.value & 0x8000 != 0;
+        flags.carry = self.carry;
+        flags.overflow = self.overflow;
+    }
+}
+
+/// Whether `opcode` works on reg1 alone, without a source operand.
+pub(crate) fn is_unary(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::INC | Opcode::DEC | Opcode::NOT)
+}
+
+/// Whether `opcode` only sets the flags, leaving reg1 as it was.
+pub(crate) fn discards_result(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::CMP | Opcode::TEST)
+}
+
+/// Computes `opcode` on `a` and `b`, with `carry` as the incoming C. Unary
+/// opcodes ignore `b`.
+///
+/// Panics if `opcode` is not an ALU opcode.
+pub(crate) fn evaluate(opcode: Opcode, a: u16, b: u16, carry: bool) -> Result<AluResult, CpuError> {
+    let result = match opcode {
+        Opcode::ADD => add(a, b, false),
+        Opcode::SUB | Opcode::CMP => sub(a, b, false),
+        Opcode::ADC => add(a, b, carry),
+        Opcode::SBC => sub(a, b, carry),
+        Opcode::INC => add(a, 1, false),
+        Opcode::DEC => sub(a, 1, false),
+        Opcode::MUL => {
+            let (value, overflowed) = a.overflowing_mul(b);
+            AluResult { value, carry: overflowed, overflow: overflowed }
+        }
+        Opcode::IMUL => {
+            let (value, overflowed) = (a as i16).overflowing_mul(b as i16);
+            AluResult { value: value as u16, carry: overflowed, overflow: overflowed }
+        }
+        Opcode::DIV | Opcode::MOD | Opcode::IDIV | Opcode::IMOD if b == 0 => return Err(CpuError::DivideByZero),
+        Opcode::DIV => AluResult::logic(a / b),
+        Opcode::MOD => AluResult::logic(a % b),
+        Opcode::IDIV => {
+            let (value, overflowed) = (a as i16).overflowing_div(b as i16);
+            AluResult { value: value as u16, carry: false, overflow: overflowed }
+        }
+        Opcode::IMOD => AluResult::logic((a as i16).wrapping_rem(b as i16) as u16),
+        Opcode::AND | Opcode::TEST => AluResult::logic(a & b),
+        Opcode::OR => AluResult::logic(a | b),
+        Opcode::XOR => AluResult::logic(a ^ b),
+        Opcode::NOT => AluResult::logic(!a),
+        Opcode::SHL => shift(a, b, |value, count| value.checked_shl(count).unwrap_or(0), 0x8000),
+        Opcode::SHR => shift(a, b, |value, count| value.checked_shr(count).unwrap_or(0), 0x0001),
+        Opcode::SAR => {
+            let sign = (a as i16 >> 15) as u16;
+            shift(a, b, |value, count| (value as i16).checked_shr(count).map_or(sign, |value| value as u16), 0x0001)
+        }
+        Opcode::RCL => rotate(a, b, carry, |bits, count| (bits << count) | (bits >> (17 - count))),
+        Opcode::RCR => rotate(a, b, carry, |bits, count| (bits >> count) | (bits << (17 - count))),
+        other => panic!("{} is not an ALU opcode", other.mnemonic()),
+    };
+    Ok(result)
+}
+
+/// Multiplies `a` by `b` for MULX, returning the low and high words of the
+/// product and setting the flags from all 32 bits.
+pub(crate) fn multiply_wide(a: u16, b: u16, flags: &mut Flags) -> (u16, u16) {
+    let product = a as u32 * b as u32;
+    let (low, high) = (product as u16, (product >> 16) as u16);
+    flags.zero = product == 0;
+    flags.negative = high & 0x8000 != 0;
+    flags.carry = high != 0;
+    flags.overflow = high != 0;
+    (low, high)
+}
+
+// The signed sum a + b + carry overflows when a and b share a sign the result doesn't.
+fn add(a: u16, b: u16, carry_in: bool) -> AluResult {
+    let sum = a as u32 + b as u32 + carry_in as u32;
+    let value = sum as u16;
+    let overflow = (a ^ value) & (b ^ value) & 0x8000 != 0;
+    AluResult { value, carry: sum > 0xFFFF, overflow }
+}
+
+// The signed difference a - b - borrow overflows when a and b differ in sign and
+// the result's sign differs from a's.
+fn sub(a: u16, b: u16, borrow_in: bool) -> AluResult {
+    let subtrahend = b as u32 + borrow_in as u32;
+    let value = (a as u32).wrapping_sub(subtrahend) as u16;
+    let overflow = (a ^ b) & (a ^ value) & 0x8000 != 0;
+    AluResult { value, carry: subtrahend > a as u32, overflow }
+}
+
+// Shifts by `count - 1` first so the bit about to leave, under `edge`, becomes the carry.
+fn shift(a: u16, count: u16, shift_by: impl Fn(u16, u32) -> u16, edge: u16) -> AluResult {
+    if count == 0 {
+        return AluResult::logic(a);
+    }
+    let almost = shift_by(a, count as u32 - 1);
+    AluResult { value: shift_by(almost, 1), carry: almost & edge != 0, overflow: false }
+}
+
+// Rotates the 17 bits C:a, with the carry as bit 16, by `count` modulo 17.
+fn rotate(a: u16, count: u16, carry: bool, rotate_by: impl Fn(u32, u32) -> u32) -> AluResult {
+    let bits = (carry as u32) << 16 | a as u32;
+    let rotated = match (count % 17) as u32 {
+        0 => bits,
+        count => rotate_by(bits, count) & 0x1_FFFF,
+    };
+    AluResult { value: rotated as u16, carry: rotated & 0x1_0000 != 0, overflow: false }
+}
@@ -0,0 +1,560 @@
+//! Two-pass text assembler producing memory images for the CPU.
+//!
+//! Each line holds optional `label:` definitions followed by an instruction,
+//! and anything after `;` is a comment. Operands select the addressing mode:
+//!
+//! ```text
+//! MOV r1, 3        ; Immediate: the value follows as a data byte
+//! ADD r1, r2       ; Register:  source register in reg2
+//! LOAD r0, [r3]    ; Indirect:  memory at the address held in reg2
+//! LOAD r0, [0x20]  ; Memory:    memory at an absolute address data byte
+//! ```
+//!
+//! Numbers may be written in decimal, `0x` hex or `0b` binary, with `_`
+//! separators. Labels can be used anywhere a number is expected, and
+//! `.byte 1, 2, 3` emits raw data bytes.
+//!
+//! `JB` and `JAE` assemble to `JC` and `JNC`, for use after an unsigned `CMP`.
+//! `MULX` only takes two different registers, as it writes to both.
+//!
+//! Instructions with a source operand switch to their wide form with a 16-bit
+//! data word when a value or label address does not fit in a byte. The CPU
+//! zero-extends data bytes, so negative values always take the wide form.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::{AddressingMode, Instruction, Opcode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    ImmediateOutOfRange(i64),
+    InvalidRegister(String),
+    InvalidOperand(String),
+    RepeatedRegister(String),
+    WrongOperandCount { expected: usize, found: usize },
+}
+
+impl AsmError {
+    fn new(line: usize, column: usize, kind: AsmErrorKind) -> Self {
+        AsmError { line, column, kind }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match &self.kind {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            AsmErrorKind::UndefinedLabel(name) => write!(f, "undefined label `{}`", name),
+            AsmErrorKind::DuplicateLabel(name) => write!(f, "label `{}` is already defined", name),
+            AsmErrorKind::ImmediateOutOfRange(value) => {
+                write!(f, "value {} does not fit in the data field", value)
+            }
+            AsmErrorKind::InvalidRegister(name) => write!(f, "invalid register `{}`", name),
+            AsmErrorKind::InvalidOperand(text) => write!(f, "invalid operand `{}`", text),
+            AsmErrorKind::RepeatedRegister(name) => write!(f, "register `{}` cannot be used twice", name),
+            AsmErrorKind::WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operand(s), found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Operand shapes accepted by each mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OperandForm {
+    None,
+    Reg,
+    Source,
+    RegSource,
+    /// Two registers, which must differ.
+    RegReg,
+}
+
+impl OperandForm {
+    pub(crate) fn of(opcode: Opcode) -> OperandForm {
+        match opcode {
+            Opcode::LOAD | Opcode::STORE | Opcode::MOV | Opcode::SWAP
+            | Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV
+            | Opcode::CMP | Opcode::ADC | Opcode::SBC
+            | Opcode::IMUL | Opcode::IDIV | Opcode::MOD | Opcode::IMOD
+            | Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::TEST
+            | Opcode::RCL | Opcode::RCR | Opcode::SAR => OperandForm::RegSource,
+            Opcode::MULX => OperandForm::RegReg,
+            Opcode::INC | Opcode::DEC | Opcode::NOT | Opcode::POP => OperandForm::Reg,
+            Opcode::JMP | Opcode::JZ | Opcode::JNZ | Opcode::JC | Opcode::CALL | Opcode::PUSH
+            | Opcode::JN | Opcode::JNN | Opcode::JO | Opcode::JNO | Opcode::JNC
+            | Opcode::JL | Opcode::JGE | Opcode::JG | Opcode::JLE | Opcode::JA => OperandForm::Source,
+            Opcode::RET | Opcode::NOP | Opcode::EI | Opcode::DI | Opcode::IRET | Opcode::HALT => OperandForm::None,
+        }
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            OperandForm::None => 0,
+            OperandForm::Reg | OperandForm::Source => 1,
+            OperandForm::RegSource | OperandForm::RegReg => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(i64),
+    Label(String),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(u8),
+    Immediate(Value),
+    Indirect(u8),
+    Memory(Value),
+}
+
+#[derive(Debug, Clone)]
+struct Spanned<T> {
+    value: T,
+    column: usize,
+}
+
+/// An instruction whose fields are known except for label references.
+#[derive(Debug)]
+struct Encoding {
+    opcode: Opcode,
+    mode: AddressingMode,
+    reg1: u8,
+    reg2: u8,
+    data: Option<Spanned<Value>>,
+    wide: bool,
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction { line: usize, encoding: Encoding },
+    Bytes { line: usize, values: Vec<Spanned<Value>> },
+}
+
+impl Statement {
+    fn len(&self) -> usize {
+        match self {
+            Statement::Instruction { encoding, .. } => match (&encoding.data, encoding.wide) {
+                (Some(_), true) => 4,
+                (Some(_), false) => 3,
+                (None, _) => 2,
+            },
+            Statement::Bytes { values, .. } => values.len(),
+        }
+    }
+
+    fn encode(&self, labels: &HashMap<String, usize>, image: &mut Vec<u8>) -> Result<(), AsmError> {
+        match self {
+            Statement::Instruction { line, encoding } => {
+                let data = match &encoding.data {
+                    Some(data) if encoding.wide => Some(resolve_word(*line, data, labels)?),
+                    Some(data) => Some(resolve_byte(*line, data, labels)? as u16),
+                    None => None,
+                };
+                let instruction = Instruction {
+                    opcode: encoding.opcode,
+                    mode: encoding.mode,
+                    reg1: encoding.reg1,
+                    reg2: encoding.reg2,
+                    data,
+                    wide: encoding.wide,
+                };
+                image.extend_from_slice(&instruction.encode());
+            }
+            Statement::Bytes { line, values } => {
+                for value in values {
+                    image.push(resolve_byte(*line, value, labels)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve(line: usize, value: &Spanned<Value>, labels: &HashMap<String, usize>) -> Result<i64, AsmError> {
+    match &value.value {
+        Value::Number(number) => Ok(*number),
+        Value::Label(name) => match labels.get(name) {
+            Some(address) => Ok(*address as i64),
+            None => Err(AsmError::new(line, value.column, AsmErrorKind::UndefinedLabel(name.clone()))),
+        },
+    }
+}
+
+fn resolve_byte(line: usize, value: &Spanned<Value>, labels: &HashMap<String, usize>) -> Result<u8, AsmError> {
+    let number = resolve(line, value, labels)?;
+
+    // Negative literals are stored as their two's complement byte.
+    if !(-128..=255).contains(&number) {
+        return Err(AsmError::new(line, value.column, AsmErrorKind::ImmediateOutOfRange(number)));
+    }
+    Ok(number as u8)
+}
+
+fn resolve_word(line: usize, value: &Spanned<Value>, labels: &HashMap<String, usize>) -> Result<u16, AsmError> {
+    let number = resolve(line, value, labels)?;
+
+    if !(-32768..=65535).contains(&number) {
+        return Err(AsmError::new(line, value.column, AsmErrorKind::ImmediateOutOfRange(number)));
+    }
+    Ok(number as u16)
+}
+
+/// An assembled image together with the address of every label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub image: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+/// Assembles `source` into a memory image starting at address 0.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_program(source).map(|program| program.image)
+}
+
+/// Like [`assemble`], also returning the label addresses for debuggers.
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    // Labels first map to the index of the statement they precede.
+    let mut positions = HashMap::new();
+    let mut statements = Vec::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let (defined, statement) = parse_line(line, text)?;
+
+        for label in defined {
+            if positions.insert(label.value.clone(), statements.len()).is_some() {
+                return Err(AsmError::new(line, label.column, AsmErrorKind::DuplicateLabel(label.value)));
+            }
+        }
+
+        if let Some(statement) = statement {
+            statements.push(statement);
+        }
+    }
+
+    // Instructions referring to labels start narrow and are widened while a
+    // label lands above 0xFF. Widening only moves labels up, so this settles.
+    let labels = loop {
+        let labels = layout(&statements, &positions);
+        let mut widened = false;
+
+        for statement in &mut statements {
+            if let Statement::Instruction { encoding, .. } = statement {
+                let address = match &encoding.data {
+                    Some(Spanned { value: Value::Label(name), .. }) => labels.get(name).copied(),
+                    _ => None,
+                };
+                if !encoding.wide && encoding.opcode.has_wide_form() && address.is_some_and(|address| address > 0xFF) {
+                    encoding.wide = true;
+                    widened = true;
+                }
+            }
+        }
+
+        if !widened {
+            break labels;
+        }
+    };
+
+    let mut image = Vec::with_capacity(statements.iter().map(Statement::len).sum());
+    for statement in &statements {
+        statement.encode(&labels, &mut image)?;
+    }
+
+    let labels = labels.into_iter().map(|(name, address)| (name, address as u16)).collect();
+    Ok(Program { image, labels })
+}
+
+fn layout(statements: &[Statement], positions: &HashMap<String, usize>) -> HashMap<String, usize> {
+    let mut addresses = Vec::with_capacity(statements.len() + 1);
+    let mut address = 0;
+    addresses.push(address);
+    for statement in statements {
+        address += statement.len();
+        addresses.push(address);
+    }
+
+    positions
+        .iter()
+        .map(|(name, index)| (name.clone(), addresses[*index]))
+        .collect()
+}
+
+fn parse_line(line: usize, text: &str) -> Result<(Vec<Spanned<String>>, Option<Statement>), AsmError> {
+    let code = match text.find(';') {
+        Some(index) => &text[..index],
+        None => text,
+    };
+
+    let mut labels = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        pos += leading_whitespace(&code[pos..]);
+        if pos == code.len() {
+            return Ok((labels, None));
+        }
+
+        let word_len = identifier_len(&code[pos..]);
+        if word_len == 0 {
+            let word = code[pos..].split_whitespace().next().unwrap_or_default();
+            return Err(AsmError::new(line, pos + 1, AsmErrorKind::UnknownMnemonic(word.to_string())));
+        }
+
+        let word = &code[pos..pos + word_len];
+        let after = pos + word_len + leading_whitespace(&code[pos + word_len..]);
+
+        if code[after..].starts_with(':') {
+            labels.push(Spanned { value: word.to_string(), column: pos + 1 });
+            pos = after + 1;
+            continue;
+        }
+
+        if word.eq_ignore_ascii_case(".byte") {
+            let operands = parse_operands(line, code, pos + word_len)?;
+            return Ok((labels, Some(build_bytes(line, pos + 1, operands)?)));
+        }
+
+        let opcode = match Opcode::from_mnemonic(word) {
+            Some(opcode) => opcode,
+            None => return Err(AsmError::new(line, pos + 1, AsmErrorKind::UnknownMnemonic(word.to_string()))),
+        };
+
+        let operands = parse_operands(line, code, pos + word_len)?;
+        let encoding = build_encoding(line, pos + 1, opcode, operands)?;
+        return Ok((labels, Some(Statement::Instruction { line, encoding })));
+    }
+}
+
+fn parse_operands(line: usize, code: &str, start: usize) -> Result<Vec<Spanned<Operand>>, AsmError> {
+    let mut operands = Vec::new();
+    if code[start..].trim().is_empty() {
+        return Ok(operands);
+    }
+
+    let mut offset = start;
+    for piece in code[start..].split(',') {
+        let column = offset + leading_whitespace(piece) + 1;
+        operands.push(Spanned { value: parse_operand(line, column, piece.trim())?, column });
+        offset += piece.len() + 1;
+    }
+    Ok(operands)
+}
+
+fn parse_operand(line: usize, column: usize, text: &str) -> Result<Operand, AsmError> {
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = match inner.strip_suffix(']') {
+            Some(inner) => inner.trim(),
+            None => return Err(AsmError::new(line, column, AsmErrorKind::InvalidOperand(text.to_string()))),
+        };
+        let inner_column = column + 1 + leading_whitespace(&text[1..]);
+
+        return match parse_register(line, inner_column, inner)? {
+            Some(register) => Ok(Operand::Indirect(register)),
+            None => Ok(Operand::Memory(parse_value(line, inner_column, inner)?)),
+        };
+    }
+
+    match parse_register(line, column, text)? {
+        Some(register) => Ok(Operand::Register(register)),
+        None => Ok(Operand::Immediate(parse_value(line, column, text)?)),
+    }
+}
+
+fn parse_register(line: usize, column: usize, text: &str) -> Result<Option<u8>, AsmError> {
+    let digits = match text.strip_prefix('r').or_else(|| text.strip_prefix('R')) {
+        Some(digits) if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => digits,
+        _ => return Ok(None),
+    };
+
+    match digits.parse::<u8>() {
+        Ok(register) if register <= 7 => Ok(Some(register)),
+        _ => Err(AsmError::new(line, column, AsmErrorKind::InvalidRegister(text.to_string()))),
+    }
+}
+
+fn parse_value(line: usize, column: usize, text: &str) -> Result<Value, AsmError> {
+    let invalid = || AsmError::new(line, column, AsmErrorKind::InvalidOperand(text.to_string()));
+
+    if !text.is_empty() && identifier_len(text) == text.len() {
+        return Ok(Value::Label(text.to_string()));
+    }
+
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let (radix, digits) = if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        (16, hex)
+    } else if let Some(bin) = unsigned.strip_prefix("0b").or_else(|| unsigned.strip_prefix("0B")) {
+        (2, bin)
+    } else {
+        (10, unsigned)
+    };
+
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return Err(invalid());
+    }
+
+    let magnitude = i64::from_str_radix(&digits, radix).map_err(|_| invalid())?;
+    Ok(Value::Number(if negative { -magnitude } else { magnitude }))
+}
+
+fn build_bytes(line: usize, column: usize, operands: Vec<Spanned<Operand>>) -> Result<Statement, AsmError> {
+    if operands.is_empty() {
+        return Err(AsmError::new(line, column, AsmErrorKind::WrongOperandCount { expected: 1, found: 0 }));
+    }
+
+    let mut values = Vec::with_capacity(operands.len());
+    for operand in operands {
+        match operand.value {
+            Operand::Immediate(value) => values.push(Spanned { value, column: operand.column }),
+            other => {
+                return Err(AsmError::new(line, operand.column, AsmErrorKind::InvalidOperand(operand_text(&other))))
+            }
+        }
+    }
+    Ok(Statement::Bytes { line, values })
+}
+
+// Besides its own operand form, every instruction accepts the explicit
+// `reg, source` form so that encodings with unused fields set still round-trip
+// through the disassembler.
+fn build_encoding(
+    line: usize,
+    column: usize,
+    opcode: Opcode,
+    operands: Vec<Spanned<Operand>>,
+) -> Result<Encoding, AsmError> {
+    let form = match (OperandForm::of(opcode), operands.len()) {
+        (form, found) if form.count() == found => form,
+        (_, 2) => OperandForm::RegSource,
+        (form, found) => {
+            return Err(AsmError::new(
+                line,
+                column,
+                AsmErrorKind::WrongOperandCount { expected: form.count(), found },
+            ))
+        }
+    };
+
+    let mut encoding = Encoding {
+        opcode,
+        mode: AddressingMode::Register,
+        reg1: 0,
+        reg2: 0,
+        data: None,
+        wide: false,
+    };
+
+    let mut operands = operands.into_iter();
+    match form {
+        OperandForm::None => {}
+        OperandForm::Reg => {
+            encoding.reg1 = expect_register(line, operands.next().unwrap())?;
+        }
+        OperandForm::Source => {
+            set_source(&mut encoding, operands.next().unwrap());
+        }
+        OperandForm::RegSource => {
+            encoding.reg1 = expect_register(line, operands.next().unwrap())?;
+            set_source(&mut encoding, operands.next().unwrap());
+        }
+        OperandForm::RegReg => {
+            encoding.reg1 = expect_register(line, operands.next().unwrap())?;
+            let operand = operands.next().unwrap();
+            let column = operand.column;
+            encoding.reg2 = expect_register(line, operand)?;
+            if encoding.reg2 == encoding.reg1 {
+                let name = format!("r{}", encoding.reg2);
+                return Err(AsmError::new(line, column, AsmErrorKind::RepeatedRegister(name)));
+            }
+        }
+    }
+
+    // Numbers outside 0..=255 need the 16-bit data word, as data bytes are zero-extended.
+    if let Some(Spanned { value: Value::Number(number), .. }) = &encoding.data {
+        encoding.wide = opcode.has_wide_form() && !(0..=255).contains(number);
+    }
+
+    Ok(encoding)
+}
+
+fn expect_register(line: usize, operand: Spanned<Operand>) -> Result<u8, AsmError> {
+    match operand.value {
+        Operand::Register(register) => Ok(register),
+        other => Err(AsmError::new(line, operand.column, AsmErrorKind::InvalidOperand(operand_text(&other)))),
+    }
+}
+
+fn set_source(encoding: &mut Encoding, operand: Spanned<Operand>) {
+    let column = operand.column;
+    match operand.value {
+        Operand::Register(register) => {
+            encoding.mode = AddressingMode::Register;
+            encoding.reg2 = register;
+        }
+        Operand::Indirect(register) => {
+            encoding.mode = AddressingMode::Indirect;
+            encoding.reg2 = register;
+        }
+        Operand::Immediate(value) => {
+            encoding.mode = AddressingMode::Immediate;
+            encoding.data = Some(Spanned { value, column });
+        }
+        Operand::Memory(value) => {
+            encoding.mode = AddressingMode::Memory;
+            encoding.data = Some(Spanned { value, column });
+        }
+    }
+}
+
+fn operand_text(operand: &Operand) -> String {
+    let value_text = |value: &Value| match value {
+        Value::Number(number) => number.to_string(),
+        Value::Label(name) => name.clone(),
+    };
+
+    match operand {
+        Operand::Register(register) => format!("r{}", register),
+        Operand::Immediate(value) => value_text(value),
+        Operand::Indirect(register) => format!("[r{}]", register),
+        Operand::Memory(value) => format!("[{}]", value_text(value)),
+    }
+}
+
+fn leading_whitespace(text: &str) -> usize {
+    text.len() - text.trim_start().len()
+}
+
+fn identifier_len(text: &str) -> usize {
+    let mut chars = text.char_indices();
+    match chars.next() {
+        Some((_, c)) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {}
+        _ => return 0,
+    }
+    chars
+        .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '.'))
+        .map(|(index, _)| index)
+        .unwrap_or(text.len())
+}
@@ -0,0 +1,270 @@
+use crate::snapshot::{write_chunk, Reader};
+use crate::{CpuError, Memory, SnapshotError, MAX_MEMORY_SIZE};
+
+/// The address space the CPU fetches from and loads and stores through.
+pub trait Bus {
+    /// Reads the byte at `address`. Reads from devices may have side effects.
+    fn read(&mut self, address: u16) -> Result<u8, CpuError>;
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), CpuError>;
+
+    /// Puts back a RAM byte overwritten by a step being undone. Device and ROM
+    /// addresses are left alone, so undoing never has side effects.
+    fn restore_byte(&mut self, address: u16, value: u8);
+
+    /// Reads the byte at `address` without side effects, for dumps and debuggers.
+    /// Returns `None` for unmapped addresses and devices that cannot be peeked.
+    fn peek(&self, address: u16) -> Option<u8>;
+
+    /// Number of addressable bytes, counted from address 0. The stack starts at the top.
+    fn size(&self) -> usize;
+
+    /// Advances time-driven devices after the CPU executes an instruction.
+    fn tick(&mut self, _cycles: u32) {}
+
+    /// Serializes RAM contents and device state for a [`Snapshot`](crate::Snapshot).
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restores state from [`save_state`](Bus::save_state) on a bus with the same layout.
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), SnapshotError>;
+}
+
+impl Bus for Memory {
+    fn read(&mut self, address: u16) -> Result<u8, CpuError> {
+        Memory::read(self, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), CpuError> {
+        Memory::write(self, address, value)
+    }
+
+    fn restore_byte(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.data.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.data.get(address as usize).copied()
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        if state.len() != self.data.len() {
+            return Err(SnapshotError::Mismatch("memory size"));
+        }
+        self.data.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+/// A memory-mapped device. Offsets are relative to the start of its region.
+pub trait Device {
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// Side-effect free read; devices whose reads change state return `None`.
+    fn peek(&self, _offset: u16) -> Option<u8> {
+        None
+    }
+
+    /// Called with the cycles spent by each executed instruction.
+    fn tick(&mut self, _cycles: u32) {}
+
+    /// Serializes the device's state for a snapshot. Stateless devices save nothing.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+/// What happens to writes into a ROM region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomWrites {
+    Fault,
+    Ignore,
+}
+
+enum RegionKind {
+    Ram(Vec<u8>),
+    Rom { data: Vec<u8>, writes: RomWrites },
+    Device(Box<dyn Device>),
+}
+
+struct Region {
+    start: usize,
+    len: usize,
+    kind: RegionKind,
+}
+
+/// A bus routing address ranges to RAM, ROM and device regions.
+///
+/// Accesses to addresses outside every region raise [`CpuError::MemoryFault`].
+pub struct MemoryMap {
+    size: usize,
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    /// An empty map spanning `size` bytes of address space.
+    ///
+    /// Panics if `size` exceeds [`MAX_MEMORY_SIZE`].
+    pub fn new(size: usize) -> Self {
+        assert!(size <= MAX_MEMORY_SIZE, "address space of {} bytes exceeds 16 bits", size);
+        MemoryMap { size, regions: Vec::new() }
+    }
+
+    /// Maps `len` bytes of zeroed RAM at `start`.
+    pub fn ram(mut self, start: u16, len: usize) -> Self {
+        self.map(start, len, RegionKind::Ram(vec![0; len]));
+        self
+    }
+
+    /// Maps `data` as read-only memory at `start`.
+    pub fn rom(mut self, start: u16, data: Vec<u8>, writes: RomWrites) -> Self {
+        self.map(start, data.len(), RegionKind::Rom { data, writes });
+        self
+    }
+
+    /// Maps `device` over `len` bytes at `start`.
+    pub fn device(mut self, start: u16, len: usize, device: Box<dyn Device>) -> Self {
+        self.map(start, len, RegionKind::Device(device));
+        self
+    }
+
+    // Panics on regions that leave the address space or overlap, both of which
+    // are mistakes in the board description rather than in the program.
+    fn map(&mut self, start: u16, len: usize, kind: RegionKind) {
+        let start = start as usize;
+        assert!(start + len <= self.size, "region 0x{:04X}+{} is outside the address space", start, len);
+        assert!(
+            self.regions.iter().all(|region| start + len <= region.start || region.start + region.len <= start),
+            "region 0x{:04X}+{} overlaps another region",
+            start,
+            len
+        );
+        self.regions.push(Region { start, len, kind });
+    }
+
+    fn region(&self, address: u16) -> Option<(&Region, u16)> {
+        let address = address as usize;
+        self.regions
+            .iter()
+            .find(|region| (region.start..region.start + region.len).contains(&address))
+            .map(|region| (region, (address - region.start) as u16))
+    }
+
+    fn region_mut(&mut self, address: u16) -> Option<(&mut Region, u16)> {
+        let address = address as usize;
+        self.regions
+            .iter_mut()
+            .find(|region| (region.start..region.start + region.len).contains(&address))
+            .map(|region| {
+                let offset = (address - region.start) as u16;
+                (region, offset)
+            })
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read(&mut self, address: u16) -> Result<u8, CpuError> {
+        match self.region_mut(address) {
+            Some((region, offset)) => match &mut region.kind {
+                RegionKind::Ram(data) | RegionKind::Rom { data, .. } => Ok(data[offset as usize]),
+                RegionKind::Device(device) => Ok(device.read(offset)),
+            },
+            None => Err(CpuError::MemoryFault { address }),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), CpuError> {
+        match self.region_mut(address) {
+            Some((region, offset)) => match &mut region.kind {
+                RegionKind::Ram(data) => {
+                    data[offset as usize] = value;
+                    Ok(())
+                }
+                RegionKind::Rom { writes: RomWrites::Ignore, .. } => Ok(()),
+                RegionKind::Rom { writes: RomWrites::Fault, .. } => Err(CpuError::ReadOnly { address }),
+                RegionKind::Device(device) => {
+                    device.write(offset, value);
+                    Ok(())
+                }
+            },
+            None => Err(CpuError::MemoryFault { address }),
+        }
+    }
+
+    fn restore_byte(&mut self, address: u16, value: u8) {
+        if let Some((Region { kind: RegionKind::Ram(data), .. }, offset)) = self.region_mut(address) {
+            data[offset as usize] = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        let (region, offset) = self.region(address)?;
+        match &region.kind {
+            RegionKind::Ram(data) | RegionKind::Rom { data, .. } => Some(data[offset as usize]),
+            RegionKind::Device(device) => device.peek(offset),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        for region in &mut self.regions {
+            if let RegionKind::Device(device) = &mut region.kind {
+                device.tick(cycles);
+            }
+        }
+    }
+
+    // One length-prefixed chunk per region in mapping order. ROM contents come
+    // from the board description, so their chunks are empty.
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        for region in &self.regions {
+            match &region.kind {
+                RegionKind::Ram(data) => write_chunk(&mut state, data),
+                RegionKind::Rom { .. } => write_chunk(&mut state, &[]),
+                RegionKind::Device(device) => write_chunk(&mut state, &device.save_state()),
+            }
+        }
+        state
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader::new(state);
+        let mut chunks = Vec::with_capacity(self.regions.len());
+        for region in &self.regions {
+            let chunk = reader.chunk()?;
+            if matches!(region.kind, RegionKind::Ram(_)) && chunk.len() != region.len {
+                return Err(SnapshotError::Mismatch("memory map"));
+            }
+            chunks.push(chunk);
+        }
+        reader.finish().map_err(|_| SnapshotError::Mismatch("memory map"))?;
+
+        for (region, chunk) in self.regions.iter_mut().zip(chunks) {
+            match &mut region.kind {
+                RegionKind::Ram(data) => data.copy_from_slice(chunk),
+                RegionKind::Rom { .. } => {}
+                RegionKind::Device(device) => device.restore_state(chunk)?,
+            }
+        }
+        Ok(())
+    }
+}
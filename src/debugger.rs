@@ -0,0 +1,392 @@
+//! Debugger driving a [`Cpu`] through its step API, plus the command language
+//! behind `rustycpu debug`:
+//!
+//! ```text
+//! break <addr>      stop before the instruction at an address executes
+//! delete <addr>     remove a breakpoint
+//! watch <addr>      stop after an instruction writes to an address
+//! step              execute one instruction
+//! next              like step, but runs a CALL until it returns
+//! finish            run until the current subroutine returns
+//! continue          run until a breakpoint, watchpoint, HALT or fault
+//! reverse-step      undo one instruction
+//! reverse-continue  undo instructions back to a breakpoint
+//! regs              print the registers
+//! flags             print the flags
+//! x/<n> <addr>      examine n bytes of memory, at most the bus size
+//! disas [addr]      list instructions from an address, or around PC
+//! ```
+//!
+//! Addresses are decimal, `0x` hex or the name of a label. Reverse execution
+//! needs a history depth, set with [`Debugger::with_history`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{Bus, Cpu, CpuError, Memory, MemoryWrite, Opcode, RegId, StepOutcome};
+
+// Instructions listed by `disas`.
+const DISAS_LINES: usize = 5;
+// Instructions `disas` lists before PC when given no address.
+const DISAS_BEFORE: usize = 2;
+// Bytes per line of `x/<n>` output.
+const EXAMINE_WIDTH: usize = 8;
+
+/// Why a debugger command gave control back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// The step, next or finish completed.
+    Done,
+    Breakpoint(u16),
+    Watchpoint(MemoryWrite),
+    Halted,
+    Fault(CpuError),
+    /// Reverse execution reached the oldest recorded step.
+    HistoryStart,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Done => Ok(()),
+            Stop::Breakpoint(address) => write!(f, "breakpoint at 0x{:04X}", address),
+            Stop::Watchpoint(write) => {
+                let old = write.old.map_or("--".to_string(), |old| format!("{:02X}", old));
+                write!(f, "watchpoint at 0x{:04X}: {} -> {:02X}", write.address, old, write.new)
+            }
+            Stop::Halted => write!(f, "halted"),
+            Stop::Fault(e) => write!(f, "fault: {}", e),
+            Stop::HistoryStart => write!(f, "start of recorded history"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    UnknownCommand(String),
+    InvalidAddress(String),
+    InvalidCount(String),
+    MissingArgument(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand(command) => write!(f, "unknown command `{}`", command),
+            CommandError::InvalidAddress(text) => write!(f, "invalid address `{}`", text),
+            CommandError::InvalidCount(text) => write!(f, "invalid count `{}`", text),
+            CommandError::MissingArgument(what) => write!(f, "missing {}", what),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub struct Debugger<B: Bus = Memory> {
+    cpu: Cpu<B>,
+    labels: BTreeMap<String, u16>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+}
+
+impl<B: Bus> Debugger<B> {
+    pub fn new(cpu: Cpu<B>) -> Self {
+        Debugger {
+            cpu,
+            labels: BTreeMap::new(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Lets commands name addresses by label, as returned by
+    /// [`assemble_program`](crate::assembler::assemble_program).
+    pub fn with_labels(mut self, labels: BTreeMap<String, u16>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Records the last `depth` steps so they can be undone, see [`Cpu::set_history_depth`].
+    pub fn with_history(mut self, depth: usize) -> Self {
+        self.cpu.set_history_depth(depth);
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu<B> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<B> {
+        &mut self.cpu
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16) {
+        self.watchpoints.insert(address);
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        self.watchpoints.remove(&address)
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) -> Stop {
+        self.run_until(|_, _| true)
+    }
+
+    /// Executes one instruction, running a CALL until it returns to the next one.
+    pub fn step_over(&mut self) -> Stop {
+        let pc = self.cpu.registers().pc();
+        match self.cpu.peek_instruction(pc) {
+            Ok(instruction) if instruction.opcode() == Opcode::CALL => {
+                let return_to = pc.wrapping_add(instruction.size() as u16);
+                let sp = self.cpu.registers().sp();
+                self.run_until(|cpu, _| cpu.registers().pc() == return_to && cpu.registers().sp() == sp)
+            }
+            _ => self.step(),
+        }
+    }
+
+    /// Runs until a RET pops the return address of the current subroutine.
+    pub fn finish(&mut self) -> Stop {
+        let depth = self.cpu.stack_depth();
+        self.run_until(|cpu, outcome| outcome.instruction.opcode() == Opcode::RET && cpu.stack_depth() < depth)
+    }
+
+    /// Runs until a breakpoint, watchpoint, HALT or fault.
+    pub fn resume(&mut self) -> Stop {
+        self.run_until(|_, _| false)
+    }
+
+    /// Undoes the last instruction.
+    pub fn step_back(&mut self) -> Stop {
+        match self.cpu.step_back() {
+            Ok(true) => Stop::Done,
+            Ok(false) => Stop::HistoryStart,
+            Err(e) => Stop::Fault(e),
+        }
+    }
+
+    /// Undoes instructions until the PC is back at a breakpoint or the history runs out.
+    pub fn reverse_continue(&mut self) -> Stop {
+        loop {
+            match self.step_back() {
+                Stop::Done => {}
+                stop => return stop,
+            }
+            let pc = self.cpu.registers().pc();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+        }
+    }
+
+    // Steps at least once, checking watchpoints, halts and breakpoints before `done`.
+    fn run_until(&mut self, mut done: impl FnMut(&Cpu<B>, &StepOutcome) -> bool) -> Stop {
+        loop {
+            let outcome = match self.cpu.step() {
+                Ok(outcome) => outcome,
+                Err(e) => return Stop::Fault(e),
+            };
+
+            if let Some(write) = outcome.writes.iter().find(|write| self.watchpoints.contains(&write.address)) {
+                return Stop::Watchpoint(*write);
+            }
+            if outcome.halted {
+                return Stop::Halted;
+            }
+            if self.breakpoints.contains(&outcome.pc_after) {
+                return Stop::Breakpoint(outcome.pc_after);
+            }
+            if done(&self.cpu, &outcome) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// Runs one command line and returns what it prints, without a trailing newline.
+    pub fn execute(&mut self, line: &str) -> Result<String, CommandError> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let argument = words.next();
+
+        match command {
+            "break" | "b" => {
+                let address = self.address(argument)?;
+                self.add_breakpoint(address);
+                Ok(format!("breakpoint at 0x{:04X}", address))
+            }
+            "delete" | "d" => {
+                let address = self.address(argument)?;
+                if self.remove_breakpoint(address) {
+                    Ok(format!("deleted breakpoint at 0x{:04X}", address))
+                } else {
+                    Ok(format!("no breakpoint at 0x{:04X}", address))
+                }
+            }
+            "watch" | "w" => {
+                let address = self.address(argument)?;
+                self.add_watchpoint(address);
+                Ok(format!("watchpoint at 0x{:04X}", address))
+            }
+            "step" | "s" => {
+                let stop = self.step();
+                Ok(self.report(stop))
+            }
+            "next" | "n" => {
+                let stop = self.step_over();
+                Ok(self.report(stop))
+            }
+            "finish" => {
+                let stop = self.finish();
+                Ok(self.report(stop))
+            }
+            "continue" | "c" => {
+                let stop = self.resume();
+                Ok(self.report(stop))
+            }
+            "reverse-step" | "rs" => {
+                let stop = self.step_back();
+                Ok(self.report(stop))
+            }
+            "reverse-continue" | "rc" => {
+                let stop = self.reverse_continue();
+                Ok(self.report(stop))
+            }
+            "regs" => Ok(self.regs()),
+            "flags" => Ok(self.flags()),
+            "disas" => {
+                let address = match argument {
+                    Some(_) => self.address(argument)?,
+                    None => self.disas_start(),
+                };
+                Ok(self.disas(address))
+            }
+            _ => match command.strip_prefix("x/") {
+                Some(count) => {
+                    let count = match count.parse() {
+                        Ok(count) if count <= self.cpu.memory().size() => count,
+                        _ => return Err(CommandError::InvalidCount(count.to_string())),
+                    };
+                    let address = self.address(argument)?;
+                    Ok(self.examine(address, count))
+                }
+                None => Err(CommandError::UnknownCommand(command.to_string())),
+            },
+        }
+    }
+
+    fn address(&self, argument: Option<&str>) -> Result<u16, CommandError> {
+        let text = argument.ok_or(CommandError::MissingArgument("address"))?;
+        if let Some(address) = self.labels.get(text) {
+            return Ok(*address);
+        }
+
+        let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => text.parse(),
+        };
+        parsed.map_err(|_| CommandError::InvalidAddress(text.to_string()))
+    }
+
+    // The stop reason followed by the instruction about to execute.
+    fn report(&self, stop: Stop) -> String {
+        let next = self.disas_line(self.cpu.registers().pc()).0;
+        match stop {
+            Stop::Done => next,
+            Stop::Halted | Stop::Fault(_) => stop.to_string(),
+            _ => format!("{}\n{}", stop, next),
+        }
+    }
+
+    fn regs(&self) -> String {
+        let lines: Vec<String> = RegId::ALL
+            .iter()
+            .map(|id| format!("{:<3}0x{:04X}", id.name(), self.cpu.registers().get(*id)))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn flags(&self) -> String {
+        let flags = self.cpu.flags();
+        format!(
+            "Z={} N={} C={} V={} I={}",
+            flags.zero() as u8,
+            flags.negative() as u8,
+            flags.carry() as u8,
+            flags.overflow() as u8,
+            flags.interrupt() as u8
+        )
+    }
+
+    fn disas(&self, mut address: u16) -> String {
+        let mut lines = Vec::with_capacity(DISAS_LINES);
+        for _ in 0..DISAS_LINES {
+            let (line, size) = self.disas_line(address);
+            lines.push(line);
+            address = address.wrapping_add(size);
+        }
+        lines.join("\n")
+    }
+
+    // Where a listing around PC starts: DISAS_BEFORE instructions back, found by
+    // decoding forward from address 0 and resyncing at every label. Lists from PC
+    // itself when that decoding does not land on it.
+    fn disas_start(&self) -> u16 {
+        let pc = self.cpu.registers().pc();
+        let labels: BTreeSet<u16> = self.labels.values().copied().collect();
+
+        let mut boundaries = Vec::new();
+        let mut address = 0;
+        while address < pc {
+            boundaries.push(address);
+            let size = self.cpu.peek_instruction(address).map_or(1, |instruction| instruction.size() as u16);
+            let next = address.saturating_add(size);
+            address = labels.range(address + 1..next).next().copied().unwrap_or(next);
+        }
+
+        if address != pc {
+            return pc;
+        }
+        let first = boundaries.len().saturating_sub(DISAS_BEFORE);
+        boundaries.get(first).copied().unwrap_or(pc)
+    }
+
+    // One listing line and the number of bytes it covers.
+    fn disas_line(&self, address: u16) -> (String, u16) {
+        let marker = if address == self.cpu.registers().pc() { "=>" } else { "  " };
+        let (text, size) = match self.cpu.peek_instruction(address) {
+            Ok(instruction) => (instruction.to_string(), instruction.size() as u16),
+            Err(_) => match self.cpu.memory().peek(address) {
+                Some(byte) => (format!(".byte 0x{:02X}", byte), 1),
+                None => ("??".to_string(), 1),
+            },
+        };
+        (format!("{} 0x{:04X}: {}", marker, address, text), size)
+    }
+
+    fn examine(&self, address: u16, count: usize) -> String {
+        let addresses: Vec<u16> = (0..count).map(|offset| address.wrapping_add(offset as u16)).collect();
+        let lines: Vec<String> = addresses
+            .chunks(EXAMINE_WIDTH)
+            .map(|row| {
+                let bytes: Vec<String> = row
+                    .iter()
+                    .map(|address| self.cpu.memory().peek(*address).map_or("--".to_string(), |byte| format!("{:02X}", byte)))
+                    .collect();
+                format!("0x{:04X}: {}", row[0], bytes.join(" "))
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,103 @@
+//! Disassembler turning memory images back into assembler listings.
+//!
+//! Bytes are decoded with the same rules as `Cpu::fetch_instruction`. Bytes
+//! that do not form a valid instruction are listed as `.byte` data and
+//! decoding resumes at the next byte, so a listing always re-assembles to the
+//! image it came from.
+
+use std::fmt;
+
+use crate::assembler::OperandForm;
+use crate::{AddressingMode, Instruction};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:<24}; {:04X}: {}", self.text, self.address, bytes.join(" "))
+    }
+}
+
+/// Decodes `image` from address 0 into one line per instruction or data byte.
+pub fn disassemble(image: &[u8]) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut address = 0;
+
+    while address < image.len() {
+        let mut bytes = image[address..].iter().copied();
+        let line = match Instruction::decode(address as u16, || bytes.next()) {
+            Ok(instruction) => {
+                let bytes = image[address..address + instruction.size()].to_vec();
+                // The assembler syntax has no place for reg2 next to a data byte, it
+                // only picks the wide form for values that need it, and it writes
+                // MULX with two distinct registers only.
+                let non_canonical_wide = instruction.wide && instruction.data.is_some_and(|data| data <= 0xFF);
+                let unwritable_reg_reg = OperandForm::of(instruction.opcode) == OperandForm::RegReg
+                    && (instruction.mode != AddressingMode::Register || instruction.reg1 == instruction.reg2);
+                let reg2_with_data = instruction.data.is_some() && instruction.reg2 != 0;
+                let text = if reg2_with_data || non_canonical_wide || unwritable_reg_reg {
+                    byte_directive(&bytes)
+                } else {
+                    instruction.to_string()
+                };
+                Line { address, bytes, text }
+            }
+            Err(_) => Line {
+                address,
+                bytes: vec![image[address]],
+                text: byte_directive(&image[address..address + 1]),
+            },
+        };
+
+        address += line.bytes.len();
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn byte_directive(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+    format!(".byte {}", values.join(", "))
+}
+
+/// Renders `image` as assembler source with addresses and raw bytes in comments.
+pub fn listing(image: &[u8]) -> String {
+    disassemble(image)
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = self.opcode.mnemonic();
+        let source = match (self.mode, self.data) {
+            (AddressingMode::Immediate, Some(data)) => data.to_string(),
+            (AddressingMode::Memory, Some(data)) if self.wide => format!("[0x{:04X}]", data),
+            (AddressingMode::Memory, Some(data)) => format!("[0x{:02X}]", data),
+            (AddressingMode::Register, _) => format!("r{}", self.reg2),
+            (AddressingMode::Indirect, _) => format!("[r{}]", self.reg2),
+            _ => return Err(fmt::Error),
+        };
+
+        // Use the mnemonic's own operand form when it reproduces the encoding,
+        // otherwise fall back to the explicit `reg, source` form.
+        match OperandForm::of(self.opcode) {
+            OperandForm::None if self.mode == AddressingMode::Register && self.reg1 == 0 && self.reg2 == 0 => {
+                write!(f, "{}", mnemonic)
+            }
+            OperandForm::Reg if self.mode == AddressingMode::Register && self.reg2 == 0 => {
+                write!(f, "{} r{}", mnemonic, self.reg1)
+            }
+            OperandForm::Source if self.reg1 == 0 => write!(f, "{} {}", mnemonic, source),
+            _ => write!(f, "{} r{}, {}", mnemonic, self.reg1, source),
+        }
+    }
+}
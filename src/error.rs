@@ -0,0 +1,40 @@
+use std::fmt;
+
+use crate::{AddressingMode, Opcode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuError {
+    InvalidOpcode { opcode: u8, pc: u16 },
+    TruncatedInstruction { pc: u16 },
+    InvalidAddressingMode { opcode: Opcode, mode: AddressingMode },
+    MemoryFault { address: u16 },
+    ReadOnly { address: u16 },
+    DivideByZero,
+    StackOverflow,
+    StackUnderflow,
+    ProgramTooLarge { size: usize, capacity: usize },
+    Halted,
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::InvalidOpcode { opcode, pc } => write!(f, "invalid opcode 0x{:02X} at 0x{:04X}", opcode, pc),
+            CpuError::TruncatedInstruction { pc } => write!(f, "truncated instruction at 0x{:04X}", pc),
+            CpuError::InvalidAddressingMode { opcode, mode } => {
+                write!(f, "{} does not support {:?} addressing", opcode.mnemonic(), mode)
+            }
+            CpuError::MemoryFault { address } => write!(f, "memory fault at 0x{:04X}", address),
+            CpuError::ReadOnly { address } => write!(f, "write to read-only memory at 0x{:04X}", address),
+            CpuError::DivideByZero => write!(f, "division by zero"),
+            CpuError::StackOverflow => write!(f, "stack overflow"),
+            CpuError::StackUnderflow => write!(f, "stack underflow"),
+            CpuError::ProgramTooLarge { size, capacity } => {
+                write!(f, "program of {} bytes does not fit in {} bytes of memory", size, capacity)
+            }
+            CpuError::Halted => write!(f, "CPU is halted"),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
@@ -0,0 +1,356 @@
+//! GDB remote serial protocol stub.
+//!
+//! Serves one debugger connection at a time over any byte stream, usually a
+//! local TCP socket (`target remote localhost:1234`). Registers are numbered
+//! r0-r7, pc, sp, bp and then the flags word, all 16 bits and little-endian,
+//! and are described to GDB with a target description. Memory is read with
+//! side-effect free peeks and written through the bus. Continuing runs until a
+//! breakpoint, HALT or fault; there is no way to interrupt a running program.
+//! Reverse stepping and continuing work within the debugger's recorded history.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use crate::debugger::{Debugger, Stop};
+use crate::{Bus, CpuError, Flags, Memory, RegId, MAX_MEMORY_SIZE};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustycpu.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="int16"/>
+    <reg name="r7" bitsize="16" type="int16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="bp" bitsize="16" type="data_ptr"/>
+    <flags id="flags_type" size="2">
+      <field name="Z" start="0" end="0"/>
+      <field name="N" start="1" end="1"/>
+      <field name="C" start="2" end="2"/>
+      <field name="V" start="3" end="3"/>
+      <field name="I" start="4" end="4"/>
+    </flags>
+    <reg name="flags" bitsize="16" type="flags_type"/>
+  </feature>
+</target>
+"#;
+
+// Largest packet GDB may send us, as advertised in qSupported.
+const PACKET_SIZE: usize = 0x1000;
+
+// Register number of the flags word, after the ones in `RegId::ALL`.
+const FLAGS_REGISTER: usize = RegId::ALL.len();
+
+pub struct GdbStub<B: Bus = Memory> {
+    debugger: Debugger<B>,
+}
+
+impl<B: Bus> GdbStub<B> {
+    pub fn new(debugger: Debugger<B>) -> Self {
+        GdbStub { debugger }
+    }
+
+    pub fn debugger(&self) -> &Debugger<B> {
+        &self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger<B> {
+        self.debugger
+    }
+
+    /// Accepts a single connection on `address` and serves it until GDB detaches.
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    /// Answers packets on `stream` until GDB detaches, kills the target or disconnects.
+    pub fn serve<S: Read + Write>(&mut self, stream: S) -> io::Result<()> {
+        let mut connection = Connection { stream };
+
+        while let Some(packet) = connection.read_packet()? {
+            match packet.as_str() {
+                "D" => {
+                    connection.write_packet("OK")?;
+                    return Ok(());
+                }
+                "k" => return Ok(()),
+                _ => {
+                    let reply = self.reply(&packet);
+                    connection.write_packet(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, packet: &str) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, arguments) = packet.split_at(1);
+        match command {
+            "?" => "S05".to_string(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(arguments),
+            "p" => self.read_register(arguments),
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "Z" | "z" => self.breakpoint(command == "Z", arguments),
+            "s" if arguments.is_empty() => stop_reply(self.debugger.step()),
+            "c" if arguments.is_empty() => stop_reply(self.debugger.resume()),
+            "b" if arguments == "s" => stop_reply(self.debugger.step_back()),
+            "b" if arguments == "c" => stop_reply(self.debugger.reverse_continue()),
+            "H" => "OK".to_string(),
+            "q" => self.query(arguments),
+            // Unsupported packets get the empty reply.
+            _ => String::new(),
+        }
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+        }
+        if query == "Attached" {
+            return "1".to_string();
+        }
+        if query == "C" {
+            return "QC1".to_string();
+        }
+
+        match query.strip_prefix("Xfer:features:read:target.xml:") {
+            Some(range) => match parse_pair(range, ',') {
+                Some((offset, length)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + length as usize).min(TARGET_XML.len());
+                    let prefix = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                    format!("{}{}", prefix, &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            },
+            None => String::new(),
+        }
+    }
+
+    fn register_value(&self, number: usize) -> Option<u16> {
+        let cpu = self.debugger.cpu();
+        match RegId::ALL.get(number) {
+            Some(id) => Some(cpu.registers().get(*id)),
+            None if number == FLAGS_REGISTER => Some(cpu.flags().bits() as u16),
+            None => None,
+        }
+    }
+
+    fn set_register_value(&mut self, number: usize, value: u16) -> bool {
+        let cpu = self.debugger.cpu_mut();
+        match RegId::ALL.get(number) {
+            Some(id) => cpu.registers_mut().set(*id, value),
+            None if number == FLAGS_REGISTER => cpu.flags = Flags::from_bits(value as u8),
+            None => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..=FLAGS_REGISTER)
+            .filter_map(|number| self.register_value(number))
+            .map(|value| hex(&value.to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let bytes = match unhex(data) {
+            Some(bytes) if bytes.len() == 2 * (FLAGS_REGISTER + 1) => bytes,
+            _ => return "E01".to_string(),
+        };
+        for (number, word) in bytes.chunks(2).enumerate() {
+            self.set_register_value(number, u16::from_le_bytes([word[0], word[1]]));
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, number: &str) -> String {
+        match usize::from_str_radix(number, 16).ok().and_then(|number| self.register_value(number)) {
+            Some(value) => hex(&value.to_le_bytes()),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let (number, value) = match arguments.split_once('=') {
+            Some((number, value)) => (usize::from_str_radix(number, 16).ok(), unhex(value)),
+            None => return "E01".to_string(),
+        };
+        match (number, value.as_deref()) {
+            (Some(number), Some(&[low, high])) if self.set_register_value(number, u16::from_le_bytes([low, high])) => {
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, arguments: &str) -> String {
+        let addresses = match parse_pair(arguments, ',').and_then(|(address, length)| memory_range(address, length)) {
+            Some(addresses) => addresses,
+            None => return "E01".to_string(),
+        };
+        let bytes: Option<Vec<u8>> = addresses.map(|address| self.debugger.cpu().memory().peek(address)).collect();
+        match bytes {
+            Some(bytes) => hex(&bytes),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let (range, data) = match arguments.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let (addresses, bytes) = match (parse_pair(range, ','), unhex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length as usize => {
+                match memory_range(address, length) {
+                    Some(addresses) => (addresses, bytes),
+                    None => return "E01".to_string(),
+                }
+            }
+            _ => return "E01".to_string(),
+        };
+
+        let memory = self.debugger.cpu_mut().memory_mut();
+        for (address, byte) in addresses.zip(bytes) {
+            if memory.write(address, byte).is_err() {
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Only software breakpoints (type 0) are supported.
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let address = match arguments.strip_prefix("0,").and_then(|rest| parse_pair(rest, ',')) {
+            Some((address, _kind)) => address as u16,
+            None => return String::new(),
+        };
+        if insert {
+            self.debugger.add_breakpoint(address);
+        } else {
+            self.debugger.remove_breakpoint(address);
+        }
+        "OK".to_string()
+    }
+}
+
+// Halts read as the program exiting; faults as the signal a real CPU would raise.
+fn stop_reply(stop: Stop) -> String {
+    let signal = match stop {
+        Stop::Done | Stop::Breakpoint(_) | Stop::Watchpoint(_) => 0x05, // SIGTRAP
+        Stop::Halted | Stop::Fault(CpuError::Halted) => return "W00".to_string(),
+        Stop::HistoryStart => return "T05replaylog:begin;".to_string(),
+        Stop::Fault(CpuError::DivideByZero) => 0x08, // SIGFPE
+        Stop::Fault(
+            CpuError::InvalidOpcode { .. }
+            | CpuError::InvalidAddressingMode { .. }
+            | CpuError::TruncatedInstruction { .. },
+        ) => 0x04, // SIGILL
+        Stop::Fault(_) => 0x0B, // SIGSEGV
+    };
+    format!("S{:02x}", signal)
+}
+
+// The addresses of an m or M packet's range, or None if it runs past the 16-bit
+// address space or holds more bytes than fit hex-encoded in one packet.
+fn memory_range(address: u32, length: u32) -> Option<impl Iterator<Item = u16>> {
+    let end = address.checked_add(length)?;
+    if end as usize > MAX_MEMORY_SIZE || length as usize > PACKET_SIZE / 2 {
+        return None;
+    }
+    Some((address..end).map(|address| address as u16))
+}
+
+fn parse_pair(text: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((u32::from_str_radix(first, 16).ok()?, u32::from_str_radix(second, 16).ok()?))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+struct Connection<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns the next packet with a valid checksum, acknowledging it, or None at
+    // end of stream. Acks from GDB and interrupt requests are skipped, and packets
+    // longer than PACKET_SIZE are refused.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        'packet: loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    // The rest of the body is skipped while looking for the next `$`.
+                    Some(_) if data.len() == PACKET_SIZE => {
+                        self.stream.write_all(b"-")?;
+                        self.stream.flush()?;
+                        continue 'packet;
+                    }
+                    Some(byte) => data.push(byte),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+
+            if expected == Some(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+            self.stream.flush()?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,76 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Number of IRQ lines. Line 0 has the highest priority.
+pub const IRQ_LINES: u8 = 8;
+
+/// Pending and masked IRQ lines plus the location of the vector table.
+///
+/// The vector table holds one little-endian handler address per line, line 0
+/// first. Clones share state, so devices can keep a handle to raise lines on.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptController {
+    state: Rc<InterruptState>,
+}
+
+#[derive(Debug, Default)]
+struct InterruptState {
+    pending: Cell<u8>,
+    masked: Cell<u8>,
+    vector_base: Cell<u16>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController::default()
+    }
+
+    /// Requests service on `line`. The request stays pending until the CPU takes it.
+    ///
+    /// Panics if `line` is not below [`IRQ_LINES`].
+    pub fn raise(&self, line: u8) {
+        assert!(line < IRQ_LINES, "IRQ line {} does not exist", line);
+        self.state.pending.set(self.state.pending.get() | (1 << line));
+    }
+
+    /// Withdraws a request on `line` that has not been serviced yet.
+    pub fn clear(&self, line: u8) {
+        self.state.pending.set(self.state.pending.get() & !(1 << line));
+    }
+
+    /// Bitmask of raised lines, including masked ones.
+    pub fn pending(&self) -> u8 {
+        self.state.pending.get()
+    }
+
+    pub(crate) fn set_pending(&self, pending: u8) {
+        self.state.pending.set(pending);
+    }
+
+    /// Bitmask of lines the CPU ignores while they are set.
+    pub fn masked(&self) -> u8 {
+        self.state.masked.get()
+    }
+
+    pub fn set_masked(&self, masked: u8) {
+        self.state.masked.set(masked);
+    }
+
+    pub fn vector_base(&self) -> u16 {
+        self.state.vector_base.get()
+    }
+
+    /// Moves the vector table, which starts at address 0 until set.
+    pub fn set_vector_base(&self, address: u16) {
+        self.state.vector_base.set(address);
+    }
+
+    /// Highest priority line that is raised and not masked.
+    pub(crate) fn next(&self) -> Option<u8> {
+        let ready = self.pending() & !self.masked();
+        match ready {
+            0 => None,
+            _ => Some(ready.trailing_zeros() as u8)
+        }
+    }
+}
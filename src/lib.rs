@@ -1,797 +1,1005 @@
-#![allow(dead_code)]
-
-use std::process::exit;
-
-const MEMORY_SIZE: usize = 256;
-
-struct Cpu {
+// The library keeps the package name, RustyCpu, as its crate name.
+#![allow(non_snake_case)]
+
+use std::collections::VecDeque;
+use std::ops::{Index, IndexMut};
+
+use trace::Tracer;
+
+mod alu;
+pub mod assembler;
+mod bus;
+pub mod debugger;
+pub mod disassembler;
+mod error;
+pub mod gdb;
+mod interrupt;
+mod snapshot;
+mod timer;
+mod timing;
+pub mod trace;
+mod uart;
+
+pub use bus::{Bus, Device, MemoryMap, RomWrites};
+pub use error::CpuError;
+pub use interrupt::{InterruptController, IRQ_LINES};
+pub use snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION};
+pub use timing::CycleCosts;
+pub use timer::{Timer, TIMER_CONTROL, TIMER_COUNTER, TIMER_ENABLE, TIMER_PERIODIC, TIMER_RELOAD};
+pub use uart::{Uart, UART_DATA, UART_RX_READY, UART_STATUS, UART_TX_READY};
+
+const DEFAULT_MEMORY_SIZE: usize = 256;
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+// Set on the opcode byte of instructions carrying a 16-bit little-endian data word.
+const WIDE_FLAG: u8 = 0x80;
+const GENERAL_REGISTERS: usize = 8;
+
+pub struct Cpu<B: Bus = Memory> {
     registers: Registers,
     flags: Flags,
-    memory: Memory,
+    memory: B,
+    interrupts: InterruptController,
     current_instruction: Option<Instruction>,
     running: bool,
+    costs: CycleCosts,
+    cycles: u64,
+    branch_taken: bool,  // Set by a conditional branch that jumps in the current step
+    reads: Vec<MemoryRead>,  // Bus reads made by the current step
+    writes: Vec<MemoryWrite>,  // Bus writes made by the current step
+    tracer: Option<Box<dyn Tracer>>,
+    history: VecDeque<UndoRecord>,  // Most recent step last
+    history_depth: usize,
+}
+
+// What `step_back` needs to take back one step.
+struct UndoRecord {
+    registers: Registers,
+    flags: Flags,
+    current_instruction: Option<Instruction>,
+    cycles: u64,
+    interrupt: Option<u8>,
+    writes: Vec<MemoryWrite>,
 }
 
 impl Default for Cpu {
     fn default() -> Self {
-        Cpu {
-            registers: Registers::default(),
-            flags: Flags::default(),
-            memory: Memory::default(),
-            current_instruction: None,
-            running: true,
-        }
+        Cpu::with_bus(Memory::default())
     }
 }
 
+/// What a single call to [`Cpu::step`] did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepOutcome {
+    pub instruction: Instruction,
+    /// Address the instruction was fetched from.
+    pub address: u16,
+    /// The instruction's encoding as fetched.
+    pub bytes: Vec<u8>,
+    /// IRQ line taken before the instruction, which is then the handler's first.
+    pub interrupt: Option<u8>,
+    pub pc_before: u16,
+    pub pc_after: u16,
+    /// Every register whose value differs after the step, `pc` included.
+    pub registers: Vec<RegisterChange>,
+    pub flags_before: Flags,
+    pub flags_after: Flags,
+    /// Cycles spent, including entering an interrupt handler.
+    pub cycles: u32,
+    /// Operand, stack and vector reads in the order they happened. Fetches are in `bytes`.
+    pub reads: Vec<MemoryRead>,
+    /// Bus writes in the order they happened, stack pushes included.
+    pub writes: Vec<MemoryWrite>,
+    pub halted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub register: RegId,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRead {
+    pub address: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    /// The byte before the write, if the bus could peek it.
+    pub old: Option<u8>,
+    pub new: u8,
+}
+
+/// Why [`Cpu::run`] returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    Halted,
+    Fault(CpuError),
+    /// The instruction or cycle limit ran out first; the CPU can keep running.
+    BudgetExhausted { pc: u16 },
+}
+
 impl Cpu {
 
-    fn debug(&self) {
-        println!("- - - DEBUG - - -");
-        println!("Registers: {:?}", self.registers);
-        println!("Flags: {:?}", self.flags);
-        println!("Memory: {:?}", self.memory.data);
-        println!("Current instruction: {:?}", self.current_instruction);
-        println!("- - - - - - - - -");
+    pub fn new() -> Self {
+        Cpu::default()
     }
 
-    fn fetch(&mut self) -> u8 {
-        let byte = self.memory.read(self.registers.pc);
-        self.registers.pc = self.registers.pc.wrapping_add(1);
-        byte
+    /// A CPU with `size` bytes of memory and the stack starting at its end.
+    ///
+    /// Panics if `size` exceeds [`MAX_MEMORY_SIZE`].
+    pub fn with_memory_size(size: usize) -> Self {
+        Cpu::with_bus(Memory::new(size))
     }
+}
 
-    fn fetch_instruction(&mut self) -> Result<Instruction, &'static str> {
+impl<B: Bus> Cpu<B> {
 
-        if self.registers.pc >= self.memory.len() as u16 {
-            return Err("Program counter out of bounds");
+    /// A CPU fetching from and storing through `bus`, with the stack starting at its top.
+    pub fn with_bus(bus: B) -> Self {
+        Cpu {
+            registers: Registers {
+                sp: bus.size() as u16, // 64 KiB wraps to 0, the first push lands at 0xFFFF
+                ..Registers::default()
+            },
+            flags: Flags::default(),
+            memory: bus,
+            interrupts: InterruptController::new(),
+            current_instruction: None,
+            running: true,
+            costs: CycleCosts::default(),
+            cycles: 0,
+            branch_taken: false,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            tracer: None,
+            history: VecDeque::new(),
+            history_depth: 0,
         }
+    }
 
-        let opcode_bin = self.fetch();
+    /// Replaces the interrupt controller, for devices wired to one before the CPU existed.
+    pub fn with_interrupts(mut self, interrupts: InterruptController) -> Self {
+        self.interrupts = interrupts;
+        self
+    }
 
-        if self.registers.pc >= self.memory.len() as u16 {
-            println!("{}, {}", self.registers.pc, self.memory.len());
-            return Err("Incomplete instruction: missing operand byte");
-        }
+    /// Replaces the default cycle cost table.
+    pub fn with_costs(mut self, costs: CycleCosts) -> Self {
+        self.costs = costs;
+        self
+    }
 
-        let operands_bin = self.fetch();
+    pub fn costs(&self) -> &CycleCosts {
+        &self.costs
+    }
 
-        let mode = (operands_bin >> 6) & 0b11;
-        let reg1 = (operands_bin >> 3) & 0b111;
-        let reg2 = operands_bin & 0b111;
+    /// Cycles spent since the CPU was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-        if reg1 >= self.registers.len() as u8 || reg2 >= self.registers.len() as u8 {
-            return Err("Invalid register number");
-        }
+    /// Sends every executed step to `tracer`. Without one, nothing is traced.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
 
+    /// Detaches and returns the tracer, for example to flush it.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
 
-        let addressing_mode = match mode {
-            0 => AddressingMode::Immediate,
-            1 => AddressingMode::Register,
-            2 => AddressingMode::Indirect,
-            3 => AddressingMode::Memory,
-            _ => panic!("Invalid addressing mode")
-        }; 
+    /// Keeps enough of the last `depth` steps to undo them with
+    /// [`step_back`](Cpu::step_back). The journal is off (depth 0) by default.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history_depth = depth;
+        while self.history.len() > depth {
+            self.history.pop_front();
+        }
+    }
 
-        let data = match addressing_mode {
-            AddressingMode::Register | AddressingMode::Indirect => None,
-            AddressingMode::Immediate | AddressingMode::Memory => {
-                if self.registers.pc >= self.memory.len() as u16 {
-                    return Err("Incomplete instruction: missing data byte");
-                }
-                Some(self.fetch())
-            }
-        };
+    pub fn history_depth(&self) -> usize {
+        self.history_depth
+    }
+
+    /// Number of steps [`step_back`](Cpu::step_back) can currently undo.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
 
-        let opcode = match Opcode::from_byte(opcode_bin) {
-            Some(opcode) => opcode,
-            None => return Err("Invalid opcode")
+    /// Undoes the most recent recorded step, restoring the registers, flags and
+    /// the memory bytes it overwrote, and re-raising an IRQ it serviced.
+    /// Returns `false` when there is no step to undo.
+    ///
+    /// Device state, such as timer counters and UART queues, is not rewound, and
+    /// writes to devices are not undone.
+    pub fn step_back(&mut self) -> Result<bool, CpuError> {
+        let record = match self.history.pop_back() {
+            Some(record) => record,
+            None => return Ok(false),
         };
 
-        Ok(Instruction {
-            opcode: opcode,
-            mode: addressing_mode,
-            reg1: reg1,
-            reg2: reg2,
-            data: data,
-        })
+        for write in record.writes.iter().rev() {
+            if let Some(old) = write.old {
+                self.memory.restore_byte(write.address, old);
+            }
+        }
+        if let Some(line) = record.interrupt {
+            self.interrupts.raise(line);
+        }
+        self.registers = record.registers;
+        self.flags = record.flags;
+        self.current_instruction = record.current_instruction;
+        self.cycles = record.cycles;
+        self.running = true;
+        Ok(true)
     }
 
-    fn execute(&mut self, instruction: Instruction) {
-        println!("{:?}", instruction);
-        self.current_instruction = Some(instruction.clone());
-        match instruction.opcode {
-            Opcode::LOAD => self.load_imediate(instruction),
-            Opcode::STORE => self.store_imediate(instruction),
-            Opcode::MOV => self.mov_imediate(instruction),
-            Opcode::SWAP => self.swap_register(instruction),
-            Opcode::ADD => self.add_imediate(instruction),
-            Opcode::SUB => self.sub_imediate(instruction),
-            Opcode::MUL => self.mul_imediate(instruction),
-            Opcode::DIV => self.div_imediate(instruction),
-            Opcode::INC => self.inc_register(instruction),
-            Opcode::DEC => self.dec_register(instruction),
-            Opcode::AND => self.and_imediate(instruction),
-            Opcode::OR => self.or_imediate(instruction),
-            Opcode::XOR => self.xor_imediate(instruction),
-            Opcode::NOT => self.not_register(instruction),
-            Opcode::SHL => self.shl_imediate(instruction),
-            Opcode::SHR => self.shr_imediate(instruction),
-            Opcode::JMP => self.jmp_imediate(instruction),
-            Opcode::JZ => self.jz_imediate(instruction),
-            Opcode::JNZ => self.jnz_imediate(instruction),
-            Opcode::JC => self.jc_imediate(instruction),
-            Opcode::CALL => self.call_imediate(instruction),
-            Opcode::RET => self.ret(instruction), // TODO: implement it so it wont take another byte as register
-            Opcode::PUSH => self.push_imediate(instruction),
-            Opcode::POP => self.pop_register(instruction),
-            Opcode::NOP => self.nop(instruction), // TODO: implement it so it wont take another byte as register
-            Opcode::HALT => self.halt(),
-            _ => panic!("Invalid opcode")
+    /// Writes `program` to the bus starting at address 0.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), CpuError> {
+        if program.len() > self.memory.size() {
+            return Err(CpuError::ProgramTooLarge { size: program.len(), capacity: self.memory.size() });
         }
+        for (address, byte) in program.iter().enumerate() {
+            self.memory.write(address as u16, *byte)?;
+        }
+        Ok(())
     }
 
-    fn run(&mut self) {
-        while self.running {
-            match self.fetch_instruction() {
-                Ok(instruction) => {
-                    self.execute(instruction);
-                },
-                Err(e) => {
-                    println!("Error: {}", e);
-                    break;
-                }
-            }
-        }
+    pub fn registers(&self) -> &Registers {
+        &self.registers
     }
 
-    fn call_imediate(&mut self, instruction: Instruction) {
-        let address = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
 
-        self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.memory.write(self.registers.sp, (self.registers.pc >> 8) as u8);
-        self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.memory.write(self.registers.sp, self.registers.pc as u8);
-        self.registers.pc = address as u16;
+    pub fn flags(&self) -> &Flags {
+        &self.flags
     }
 
-    fn nop(&mut self, instruction: Instruction) {
-        // Do nothing
+    pub fn memory(&self) -> &B {
+        &self.memory
     }
 
-    fn pop_register(&mut self, instruction: Instruction) {
-        let reg1 = match instruction.reg1 {
-            0 => &mut self.registers.r0,
-            1 => &mut self.registers.r1,
-            2 => &mut self.registers.r2,
-            3 => &mut self.registers.r3,
-            4 => &mut self.registers.r4,
-            5 => &mut self.registers.r6,
-            7 => &mut self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    pub fn memory_mut(&mut self) -> &mut B {
+        &mut self.memory
+    }
 
-        *reg1 = self.memory.read(self.registers.sp) as u16;
-        self.registers.sp = self.registers.sp.wrapping_add(1);
+    /// The controller devices raise IRQ lines on; clone it to hand it to a device.
+    pub fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
     }
 
-    fn push_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    pub fn current_instruction(&self) -> Option<&Instruction> {
+        self.current_instruction.as_ref()
+    }
 
-        self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.memory.write(self.registers.sp, data);
+    pub fn is_running(&self) -> bool {
+        self.running
     }
 
-    fn ret(&mut self, _instruction: Instruction) {
-        let low = self.memory.read(self.registers.sp) as u16;
-        self.registers.sp = self.registers.sp.wrapping_add(1);
-        let high = self.memory.read(self.registers.sp) as u16;
-        self.registers.sp = self.registers.sp.wrapping_add(1);
-        self.registers.pc = (high << 8) | low;
+    /// Captures the registers, flags, run state, pending interrupts and the bus,
+    /// RAM and device state included.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            flags: self.flags,
+            running: self.running,
+            current_instruction: self.current_instruction.clone(),
+            cycles: self.cycles,
+            pending_irqs: self.interrupts.pending(),
+            masked_irqs: self.interrupts.masked(),
+            vector_base: self.interrupts.vector_base(),
+            bus: self.memory.save_state(),
+        }
     }
 
-    fn jc_imediate(&mut self, instruction: Instruction) {
-        let address = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    /// Puts the machine back in the state `snapshot` was taken in. The bus must
+    /// be laid out like the one the snapshot came from.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        self.memory.restore_state(&snapshot.bus)?;
+        self.registers = snapshot.registers;
+        self.flags = snapshot.flags;
+        self.running = snapshot.running;
+        self.current_instruction = snapshot.current_instruction.clone();
+        self.cycles = snapshot.cycles;
+        self.interrupts.set_pending(snapshot.pending_irqs);
+        self.interrupts.set_masked(snapshot.masked_irqs);
+        self.interrupts.set_vector_base(snapshot.vector_base);
+        self.history.clear();
+        Ok(())
+    }
 
-        if self.flags.carry {
-            self.registers.pc = address as u16;
+    pub fn debug(&self) {
+        println!("- - - DEBUG - - -");
+        println!("Registers: {:?}", self.registers);
+        println!("Flags: {:?}", self.flags);
+        println!("Memory:");
+        for row in (0..self.memory.size()).step_by(16) {
+            let bytes: Vec<Option<u8>> = (row..self.memory.size().min(row + 16))
+                .map(|address| self.memory.peek(address as u16))
+                .collect();
+            if bytes.iter().any(|byte| byte.is_some_and(|byte| byte != 0)) {
+                let hex: Vec<String> = bytes
+                    .iter()
+                    .map(|byte| byte.map_or("--".to_string(), |byte| format!("{:02X}", byte)))
+                    .collect();
+                println!("  {:04X}: {}", row, hex.join(" "));
+            }
         }
+        println!("Current instruction: {:?}", self.current_instruction);
+        println!("- - - - - - - - -");
     }
 
-    fn jnz_imediate(&mut self, instruction: Instruction) {
-        let address = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn fetch(&mut self) -> Result<u8, CpuError> {
+        let byte = self.memory.read(self.registers.pc)?;
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        Ok(byte)
+    }
+
+    // Decodes the instruction at `pc`, also returning the bytes it was fetched from.
+    fn fetch_instruction(&mut self) -> Result<(Instruction, Vec<u8>), CpuError> {
+        let pc = self.registers.pc;
 
-        if !self.flags.zero {
-            self.registers.pc = address as u16;
+        if pc as usize >= self.memory.size() {
+            return Err(CpuError::MemoryFault { address: pc });
         }
+
+        let mut bytes = Vec::with_capacity(4);
+        let instruction = Instruction::decode(pc, || {
+            let byte = self.fetch().ok()?;
+            bytes.push(byte);
+            Some(byte)
+        })?;
+        Ok((instruction, bytes))
     }
 
-    fn jz_imediate(&mut self, instruction: Instruction) {
-        let address = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    /// Decodes the instruction at `address` without executing it or reading devices.
+    pub fn peek_instruction(&self, address: u16) -> Result<Instruction, CpuError> {
+        let mut next = address;
+        Instruction::decode(address, || {
+            let byte = self.memory.peek(next)?;
+            next = next.wrapping_add(1);
+            Some(byte)
+        })
+    }
 
-        if self.flags.zero {
-            self.registers.pc = address as u16;
-        }
+    // Register fields are decoded from three bits, so every index names a general register.
+    fn register(&self, index: u8) -> u16 {
+        self.registers[RegId::GENERAL[index as usize]]
     }
 
-    fn jmp_imediate(&mut self, instruction: Instruction) {
-        let address = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn set_register(&mut self, index: u8, value: u16) {
+        self.registers[RegId::GENERAL[index as usize]] = value;
+    }
 
-        self.registers.pc = address as u16;
+    // Reads through the bus, logging the read for the step outcome.
+    fn read_memory(&mut self, address: u16) -> Result<u8, CpuError> {
+        let value = self.memory.read(address)?;
+        self.reads.push(MemoryRead { address, value });
+        Ok(value)
     }
 
-    fn shl_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    // Writes through the bus, logging the write for the step outcome.
+    fn write_memory(&mut self, address: u16, value: u8) -> Result<(), CpuError> {
+        let old = self.memory.peek(address);
+        self.memory.write(address, value)?;
+        self.writes.push(MemoryWrite { address, old, new: value });
+        Ok(())
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    // The stack grows down from the end of memory; `sp` points at the last pushed byte.
+    // Depth is taken modulo 64 KiB so a full-size memory, where `sp` starts at 0, works too.
+    pub(crate) fn stack_depth(&self) -> usize {
+        self.memory.size().wrapping_sub(self.registers.sp as usize) % MAX_MEMORY_SIZE
+    }
 
-        let result = reg1 << data;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn push_byte(&mut self, value: u8) -> Result<(), CpuError> {
+        if self.stack_depth() + 1 > self.memory.size() {
+            return Err(CpuError::StackOverflow);
+        }
+        let sp = self.registers.sp.wrapping_sub(1);
+        self.write_memory(sp, value)?;
+        self.registers.sp = sp;
+        Ok(())
+    }
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+    fn pop_byte(&mut self) -> Result<u8, CpuError> {
+        if self.stack_depth() < 1 {
+            return Err(CpuError::StackUnderflow);
+        }
+        let value = self.read_memory(self.registers.sp)?;
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        Ok(value)
     }
 
-    fn shr_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    // Words are pushed high byte first, leaving the low byte at `sp`.
+    fn push_word(&mut self, value: u16) -> Result<(), CpuError> {
+        if self.stack_depth() + 2 > self.memory.size() {
+            return Err(CpuError::StackOverflow);
+        }
+        self.push_byte((value >> 8) as u8)?;
+        self.push_byte(value as u8)
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn pop_word(&mut self) -> Result<u16, CpuError> {
+        if self.stack_depth() < 2 {
+            return Err(CpuError::StackUnderflow);
+        }
+        let low = self.pop_byte()? as u16;
+        let high = self.pop_byte()? as u16;
+        Ok((high << 8) | low)
+    }
 
-        let result = reg1 >> data;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn execute(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.current_instruction = Some(instruction.clone());
+        match instruction.opcode {
+            Opcode::LOAD => self.load(instruction),
+            Opcode::STORE => self.store(instruction),
+            Opcode::MOV => self.mov(instruction),
+            Opcode::SWAP => self.swap(instruction),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::INC | Opcode::DEC | Opcode::CMP
+            | Opcode::ADC | Opcode::SBC | Opcode::IMUL | Opcode::IDIV | Opcode::MOD | Opcode::IMOD
+            | Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::NOT | Opcode::SHL | Opcode::SHR | Opcode::TEST
+            | Opcode::RCL | Opcode::RCR | Opcode::SAR => self.alu(instruction),
+            Opcode::MULX => self.mulx(instruction),
+            Opcode::JMP => self.jmp(instruction),
+            Opcode::JZ => self.jz(instruction),
+            Opcode::JNZ => self.jnz(instruction),
+            Opcode::JC => self.jc(instruction),
+            Opcode::JN => self.jn(instruction),
+            Opcode::JNN => self.jnn(instruction),
+            Opcode::JO => self.jo(instruction),
+            Opcode::JNO => self.jno(instruction),
+            Opcode::JNC => self.jnc(instruction),
+            Opcode::JL => self.jl(instruction),
+            Opcode::JGE => self.jge(instruction),
+            Opcode::JG => self.jg(instruction),
+            Opcode::JLE => self.jle(instruction),
+            Opcode::JA => self.ja(instruction),
+            Opcode::CALL => self.call(instruction),
+            // RET and NOP keep their operand byte so every instruction decodes to at least two bytes.
+            Opcode::RET => self.ret(instruction),
+            Opcode::PUSH => self.push(instruction),
+            Opcode::POP => self.pop(instruction),
+            Opcode::NOP => self.nop(instruction),
+            Opcode::EI => self.ei(instruction),
+            Opcode::DI => self.di(instruction),
+            Opcode::IRET => self.iret(instruction),
+            Opcode::HALT => self.halt(),
+        }
+    }
+
+    // Computes an ALU opcode on reg1 and the source operand, setting the flags
+    // as specified in the `alu` module. CMP and TEST only set the flags.
+    fn alu(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let reg1 = self.register(instruction.reg1);
+        let operand = if alu::is_unary(instruction.opcode) { 0 } else { self.operand(&instruction)? };
+
+        let result = alu::evaluate(instruction.opcode, reg1, operand, self.flags.carry)?;
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+        if !alu::discards_result(instruction.opcode) {
+            self.set_register(instruction.reg1, result.value);
+        }
+        result.apply(&mut self.flags);
+        Ok(())
     }
 
-    fn not_register(&mut self, instruction: Instruction) {
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    // Multiplies reg1 by reg2 into a 32-bit product, low word in reg1 and high word in reg2.
+    // With reg1 and reg2 the same register only the high word is kept; the assembler refuses that form.
+    fn mulx(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        if instruction.mode != AddressingMode::Register {
+            return Err(CpuError::InvalidAddressingMode { opcode: Opcode::MULX, mode: instruction.mode });
+        }
+        let reg1 = self.register(instruction.reg1);
+        let reg2 = self.register(instruction.reg2);
 
-        let result = !reg1;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+        let (low, high) = alu::multiply_wide(reg1, reg2, &mut self.flags);
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+        self.set_register(instruction.reg1, low);
+        self.set_register(instruction.reg2, high);
+        Ok(())
     }
 
-    fn xor_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    // Memory address named by an Indirect or Memory operand.
+    fn operand_address(&self, instruction: &Instruction) -> Result<Option<u16>, CpuError> {
+        match instruction.mode {
+            AddressingMode::Indirect => Ok(Some(self.register(instruction.reg2))),
+            AddressingMode::Memory => Ok(instruction.data),
+            AddressingMode::Immediate | AddressingMode::Register => Ok(None),
+        }
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    // Source value: the data byte or word, register reg2 or the memory byte the operand points at.
+    fn operand(&mut self, instruction: &Instruction) -> Result<u16, CpuError> {
+        match instruction.mode {
+            // Decoding guarantees a data byte for Immediate and Memory operands.
+            AddressingMode::Immediate => Ok(instruction.data.unwrap_or(0)),
+            AddressingMode::Register => Ok(self.register(instruction.reg2)),
+            AddressingMode::Indirect | AddressingMode::Memory => {
+                let address = self.operand_address(instruction)?.unwrap_or(0);
+                Ok(self.read_memory(address)? as u16)
+            }
+        }
+    }
 
-        let result = reg1 ^ data as u16;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    // Saves pc and then the flags, disables interrupts and jumps through the vector for `line`.
+    fn service_interrupt(&mut self, line: u8) -> Result<(), CpuError> {
+        let vector = self.interrupts.vector_base().wrapping_add(2 * line as u16);
+        let low = self.read_memory(vector)? as u16;
+        let high = self.read_memory(vector.wrapping_add(1))? as u16;
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+        self.push_word(self.registers.pc)?;
+        self.push_word(self.flags.bits() as u16)?;
+        self.interrupts.clear(line);
+        self.flags.interrupt = false;
+        self.registers.pc = (high << 8) | low;
+        Ok(())
     }
 
-    fn and_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    /// Fetches, decodes and executes the instruction at `pc`.
+    ///
+    /// With the interrupt flag set, the highest priority unmasked IRQ is taken
+    /// first and the step executes the first instruction of its handler.
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        if !self.running {
+            return Err(CpuError::Halted);
+        }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+        let registers_before = self.registers;
+        let flags_before = self.flags;
+        let instruction_before = self.current_instruction.clone();
+        let cycles_before = self.cycles;
+        self.branch_taken = false;
+        self.reads.clear();
+        self.writes.clear();
+
+        let mut cycles = 0;
+        let interrupt = if self.flags.interrupt { self.interrupts.next() } else { None };
+        if let Some(line) = interrupt {
+            self.service_interrupt(line)?;
+            cycles += self.costs.interrupt();
+        }
 
-        let result = reg1 & data as u16;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
+        let address = self.registers.pc;
+        let (instruction, bytes) = self.fetch_instruction()?;
+        self.execute(instruction.clone())?;
+        cycles += self.costs.instruction(&instruction, self.branch_taken);
+        self.cycles += cycles as u64;
+        self.memory.tick(cycles);
+
+        let registers = RegId::ALL
+            .iter()
+            .filter(|id| registers_before[**id] != self.registers[**id])
+            .map(|id| RegisterChange { register: *id, old: registers_before[*id], new: self.registers[*id] })
+            .collect();
+
+        let outcome = StepOutcome {
+            instruction,
+            address,
+            bytes,
+            interrupt,
+            pc_before: registers_before.pc,
+            pc_after: self.registers.pc,
+            registers,
+            flags_before,
+            flags_after: self.flags,
+            cycles,
+            reads: std::mem::take(&mut self.reads),
+            writes: std::mem::take(&mut self.writes),
+            halted: !self.running,
         };
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+        if self.history_depth > 0 {
+            if self.history.len() == self.history_depth {
+                self.history.pop_front();
+            }
+            self.history.push_back(UndoRecord {
+                registers: registers_before,
+                flags: flags_before,
+                current_instruction: instruction_before,
+                cycles: cycles_before,
+                interrupt,
+                writes: outcome.writes.clone(),
+            });
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&outcome);
+        }
+        Ok(outcome)
     }
 
-    fn or_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    /// Steps until the CPU executes `HALT` or faults.
+    pub fn run(&mut self) -> HaltReason {
+        while self.running {
+            if let Err(e) = self.step() {
+                return HaltReason::Fault(e);
+            }
+        }
+        HaltReason::Halted
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    /// Like [`run`](Cpu::run), but stops after executing `max_instructions`.
+    pub fn run_with_limit(&mut self, max_instructions: u64) -> HaltReason {
+        let mut executed = 0;
+        self.run_while(|_| {
+            executed += 1;
+            executed <= max_instructions
+        })
+    }
 
-        let result = reg1 | data as u16;
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    /// Like [`run`](Cpu::run), but stops once at least `cycles` more cycles have passed.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> HaltReason {
+        let end = self.cycles.saturating_add(cycles);
+        self.run_while(|cpu| cpu.cycles < end)
+    }
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
+    // Steps while `budget` allows another instruction.
+    fn run_while(&mut self, mut budget: impl FnMut(&Self) -> bool) -> HaltReason {
+        while self.running {
+            if !budget(self) {
+                return HaltReason::BudgetExhausted { pc: self.registers.pc };
+            }
+            if let Err(e) = self.step() {
+                return HaltReason::Fault(e);
+            }
+        }
+        HaltReason::Halted
     }
 
-    fn store_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn call(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let address = self.operand(&instruction)?;
+        self.push_word(self.registers.pc)?;
+        self.registers.pc = address;
+        Ok(())
+    }
 
-        let address = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn ei(&mut self, _instruction: Instruction) -> Result<(), CpuError> {
+        self.flags.interrupt = true;
+        Ok(())
+    }
 
-        self.memory.write(address, data);
+    fn di(&mut self, _instruction: Instruction) -> Result<(), CpuError> {
+        self.flags.interrupt = false;
+        Ok(())
     }
 
-    fn load_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn iret(&mut self, _instruction: Instruction) -> Result<(), CpuError> {
+        let flags = self.pop_word()?;
+        self.registers.pc = self.pop_word()?;
+        self.flags = Flags::from_bits(flags as u8);
+        Ok(())
+    }
 
-        match instruction.reg1 {
-            0 => self.registers.r0 = data as u16,
-            1 => self.registers.r1 = data as u16,
-            2 => self.registers.r2 = data as u16,
-            3 => self.registers.r3 = data as u16,
-            4 => self.registers.r4 = data as u16,
-            5 => self.registers.r6 = data as u16,
-            7 => self.registers.r7 = data as u16,
-            _ => panic!("Invalid register number")
-        };
+    fn nop(&mut self, _instruction: Instruction) -> Result<(), CpuError> {
+        // Do nothing
+        Ok(())
+    }
 
-        self.flags.zero = data == 0;
+    fn pop(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let value = self.pop_word()?;
+        self.set_register(instruction.reg1, value);
+        Ok(())
     }
 
-    fn mov_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn push(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let value = self.operand(&instruction)?;
+        self.push_word(value)?;
+        Ok(())
+    }
 
-        match instruction.reg1 {
-            0 => self.registers.r0 = data as u16,
-            1 => self.registers.r1 = data as u16,
-            2 => self.registers.r2 = data as u16,
-            3 => self.registers.r3 = data as u16,
-            4 => self.registers.r4 = data as u16,
-            5 => self.registers.r6 = data as u16,
-            7 => self.registers.r7 = data as u16,
-            _ => panic!("Invalid register number")
-        };
+    fn ret(&mut self, _instruction: Instruction) -> Result<(), CpuError> {
+        self.registers.pc = self.pop_word()?;
+        Ok(())
+    }
 
-        self.flags.zero = data == 0;
+    // Jumps to the operand if `condition` holds, noting the taken branch for its cycle cost.
+    fn branch(&mut self, instruction: Instruction, condition: bool) -> Result<(), CpuError> {
+        let address = self.operand(&instruction)?;
 
+        if condition {
+            self.registers.pc = address;
+            self.branch_taken = true;
+        }
+        Ok(())
     }
 
-    fn add_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn jc(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.carry)
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn jnz(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.zero)
+    }
 
-        let result = reg1.wrapping_add(data as u16);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn jz(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.zero)
+    }
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result < reg1;
-        self.flags.overflow = result < reg1;
-    }
-
-    fn swap_register(&mut self, instruction: Instruction) {
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn jn(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.negative)
+    }
 
-        let reg2 = match instruction.reg2 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn jnn(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.negative)
+    }
 
-        match instruction.reg1 {
-            0 => self.registers.r0 = reg2,
-            1 => self.registers.r1 = reg2,
-            2 => self.registers.r2 = reg2,
-            3 => self.registers.r3 = reg2,
-            4 => self.registers.r4 = reg2,
-            5 => self.registers.r6 = reg2,
-            7 => self.registers.r7 = reg2,
-            _ => panic!("Invalid register number")
-        };
+    fn jo(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.overflow)
+    }
 
-        match instruction.reg2 {
-            0 => self.registers.r0 = reg1,
-            1 => self.registers.r1 = reg1,
-            2 => self.registers.r2 = reg1,
-            3 => self.registers.r3 = reg1,
-            4 => self.registers.r4 = reg1,
-            5 => self.registers.r6 = reg1,
-            7 => self.registers.r7 = reg1,
-            _ => panic!("Invalid register number")
-        };
+    fn jno(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.overflow)
     }
 
-    fn sub_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn jnc(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.carry)
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn jl(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.negative != self.flags.overflow)
+    }
 
-        let result = reg1.wrapping_sub(data as u16);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn jge(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.negative == self.flags.overflow)
+    }
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result > reg1;
-        self.flags.overflow = result > reg1;
-    }   
+    fn jg(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.zero && self.flags.negative == self.flags.overflow)
+    }
 
-    fn mul_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
-        };
+    fn jle(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, self.flags.zero || self.flags.negative != self.flags.overflow)
+    }
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn ja(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        self.branch(instruction, !self.flags.carry && !self.flags.zero)
+    }
 
-        let result = reg1.wrapping_mul(data as u16);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn jmp(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let address = self.operand(&instruction)?;
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result > reg1;
-        self.flags.overflow = result > reg1;
+        self.registers.pc = address;
+        Ok(())
     }
 
-    fn div_imediate(&mut self, instruction: Instruction) {
-        let data = match instruction.data {
-            Some(data) => data,
-            None => return
+    fn store(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        // A bracketed operand is the destination and reg1 the value; otherwise
+        // reg1 holds the destination address and the operand is the value.
+        let (address, value) = match self.operand_address(&instruction)? {
+            Some(address) => (address, self.register(instruction.reg1)),
+            None => (self.register(instruction.reg1), self.operand(&instruction)?),
         };
 
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+        self.write_memory(address, value as u8)?;
+        Ok(())
+    }
 
-        let result = reg1.wrapping_div(data as u16);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+    fn load(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let value = self.operand(&instruction)?;
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result > reg1;
-        self.flags.overflow = result > reg1;
-    }
-
-    fn inc_register(&mut self, instruction: Instruction) {
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+        self.set_register(instruction.reg1, value);
 
-        let result = reg1.wrapping_add(1);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
-        };
+        self.flags.zero = value == 0;
+        Ok(())
+    }
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result < reg1;
-        self.flags.overflow = result < reg1;
-    }
-
-    fn dec_register(&mut self, instruction: Instruction) {
-        let reg1 = match instruction.reg1 {
-            0 => self.registers.r0,
-            1 => self.registers.r1,
-            2 => self.registers.r2,
-            3 => self.registers.r3,
-            4 => self.registers.r4,
-            5 => self.registers.r6,
-            7 => self.registers.r7,
-            _ => panic!("Invalid register number")
-        };
+    fn mov(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let value = self.operand(&instruction)?;
 
-        let result = reg1.wrapping_sub(1);
-
-        match instruction.reg1 {
-            0 => self.registers.r0 = result,
-            1 => self.registers.r1 = result,
-            2 => self.registers.r2 = result,
-            3 => self.registers.r3 = result,
-            4 => self.registers.r4 = result,
-            5 => self.registers.r6 = result,
-            7 => self.registers.r7 = result,
-            _ => panic!("Invalid register number")
+        self.set_register(instruction.reg1, value);
+
+        self.flags.zero = value == 0;
+        Ok(())
+    }
+
+    fn swap(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let reg1 = self.register(instruction.reg1);
+
+        // Swapping with memory exchanges reg1 with the byte the operand points at.
+        let other = match (instruction.mode, self.operand_address(&instruction)?) {
+            (AddressingMode::Register, _) => {
+                let reg2 = self.register(instruction.reg2);
+                self.set_register(instruction.reg2, reg1);
+                reg2
+            }
+            (_, Some(address)) => {
+                let byte = self.read_memory(address)?;
+                self.write_memory(address, reg1 as u8)?;
+                byte as u16
+            }
+            (mode, None) => return Err(CpuError::InvalidAddressingMode { opcode: Opcode::SWAP, mode }),
         };
 
-        self.flags.zero = result == 0;
-        self.flags.negative = result > 0x7FFF;
-        self.flags.carry = result < reg1;
-        self.flags.overflow = result < reg1;
+        self.set_register(instruction.reg1, other);
+        Ok(())
     }
 
-    fn halt(&mut self) {
+    fn halt(&mut self) -> Result<(), CpuError> {
         self.running = false;
+        Ok(())
     }
 }
 
-struct Memory {
-    data: [u8; MEMORY_SIZE]
+pub struct Memory {
+    data: Vec<u8>
 }
 
 impl Default for Memory {
     fn default() -> Self {
-        Memory {
-            data: [0; MEMORY_SIZE]
-        }
+        Memory::new(DEFAULT_MEMORY_SIZE)
     }
 }
 
 impl Memory {
-    fn read(&self, address: u16) -> u8 {
-        self.data[address as usize]
+    /// Zeroed memory of `size` bytes, addressed from 0.
+    ///
+    /// Panics if `size` exceeds [`MAX_MEMORY_SIZE`].
+    pub fn new(size: usize) -> Self {
+        assert!(size <= MAX_MEMORY_SIZE, "memory size {} exceeds the 16-bit address space", size);
+        Memory {
+            data: vec![0; size]
+        }
     }
 
-    fn write(&mut self, address: u16, data: u8) {
-        self.data[address as usize] = data;
+    pub fn read(&self, address: u16) -> Result<u8, CpuError> {
+        match self.data.get(address as usize) {
+            Some(byte) => Ok(*byte),
+            None => Err(CpuError::MemoryFault { address })
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) -> Result<(), CpuError> {
+        match self.data.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = data;
+                Ok(())
+            }
+            None => Err(CpuError::MemoryFault { address })
+        }
     }
 
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
 }
 
-#[derive(Debug, Clone)]
-struct Instruction {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
     opcode: Opcode,
     mode: AddressingMode,
     reg1: u8,
     reg2: u8,
-    data: Option<u8>,  // For immediate values or addresses
+    data: Option<u16>,  // For immediate values or addresses
+    wide: bool,  // Data was encoded as a 16-bit word
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn mode(&self) -> AddressingMode {
+        self.mode
+    }
+
+    pub fn reg1(&self) -> u8 {
+        self.reg1
+    }
+
+    pub fn reg2(&self) -> u8 {
+        self.reg2
+    }
+
+    pub fn data(&self) -> Option<u16> {
+        self.data
+    }
+
+    pub fn is_wide(&self) -> bool {
+        self.wide
+    }
+
+    /// The bytes [`decode`](Instruction::decode) reads this instruction from.
+    pub fn encode(&self) -> Vec<u8> {
+        let mode = match self.mode {
+            AddressingMode::Immediate => 0,
+            AddressingMode::Register => 1,
+            AddressingMode::Indirect => 2,
+            AddressingMode::Memory => 3,
+        };
+
+        let opcode = if self.wide { self.opcode as u8 | WIDE_FLAG } else { self.opcode as u8 };
+        let mut bytes = vec![opcode, (mode << 6) | (self.reg1 << 3) | self.reg2];
+        match self.data {
+            Some(data) if self.wide => bytes.extend_from_slice(&data.to_le_bytes()),
+            Some(data) => bytes.push(data as u8),
+            None => {}
+        }
+        bytes
+    }
+
+    // Decodes one instruction from a byte source: opcode byte, mode/reg1/reg2 byte
+    // and a data byte for the Immediate and Memory modes, or a little-endian data
+    // word when the opcode byte has the wide flag set.
+    fn decode(pc: u16, mut next: impl FnMut() -> Option<u8>) -> Result<Instruction, CpuError> {
+        let opcode_bin = match next() {
+            Some(byte) => byte,
+            None => return Err(CpuError::MemoryFault { address: pc })
+        };
+
+        let operands_bin = match next() {
+            Some(byte) => byte,
+            None => return Err(CpuError::TruncatedInstruction { pc })
+        };
+
+        let mode = (operands_bin >> 6) & 0b11;
+        let reg1 = (operands_bin >> 3) & 0b111;
+        let reg2 = operands_bin & 0b111;
+
+        // Two bits can only hold one of the four modes.
+        let addressing_mode = AddressingMode::from_byte(mode).unwrap();
+
+        let wide = opcode_bin & WIDE_FLAG != 0;
+
+        let data = match addressing_mode {
+            AddressingMode::Register | AddressingMode::Indirect => None,
+            AddressingMode::Immediate | AddressingMode::Memory => {
+                let low = match next() {
+                    Some(byte) => byte as u16,
+                    None => return Err(CpuError::TruncatedInstruction { pc })
+                };
+                let high = if wide {
+                    match next() {
+                        Some(byte) => byte as u16,
+                        None => return Err(CpuError::TruncatedInstruction { pc })
+                    }
+                } else {
+                    0
+                };
+                Some((high << 8) | low)
+            }
+        };
+
+        let opcode = match Opcode::from_byte(opcode_bin & !WIDE_FLAG) {
+            Some(opcode) if !wide || (opcode.has_wide_form() && data.is_some()) => opcode,
+            _ => return Err(CpuError::InvalidOpcode { opcode: opcode_bin, pc })
+        };
+
+        Ok(Instruction {
+            opcode,
+            mode: addressing_mode,
+            reg1,
+            reg2,
+            data,
+            wide,
+        })
+    }
+
+    /// Encoded size in bytes.
+    pub fn size(&self) -> usize {
+        match (self.data, self.wide) {
+            (Some(_), true) => 4,
+            (Some(_), false) => 3,
+            (None, _) => 2,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-enum AddressingMode {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
     Register,
     Immediate,
     Indirect,
@@ -799,7 +1007,7 @@ enum AddressingMode {
 }
 
 impl AddressingMode {
-    fn from_byte(byte: u8) -> Option<AddressingMode> {
+    pub fn from_byte(byte: u8) -> Option<AddressingMode> {
         match byte {
             1 => Some(AddressingMode::Register),
             0 => Some(AddressingMode::Immediate),
@@ -811,16 +1019,73 @@ impl AddressingMode {
     
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Registers {
-    r0: u16,
-    r1: u16,
-    r2: u16,
-    r3: u16,
-    r4: u16,
-    r5: u16,
-    r6: u16,
-    r7: u16,
+/// Names a register: the general purpose r0-r7 or one of `pc`, `sp` and `bp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegId {
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    PC,
+    SP,
+    BP,
+}
+
+impl RegId {
+    pub const GENERAL: [RegId; GENERAL_REGISTERS] = [
+        RegId::R0,
+        RegId::R1,
+        RegId::R2,
+        RegId::R3,
+        RegId::R4,
+        RegId::R5,
+        RegId::R6,
+        RegId::R7,
+    ];
+
+    pub const ALL: [RegId; 11] = [
+        RegId::R0,
+        RegId::R1,
+        RegId::R2,
+        RegId::R3,
+        RegId::R4,
+        RegId::R5,
+        RegId::R6,
+        RegId::R7,
+        RegId::PC,
+        RegId::SP,
+        RegId::BP,
+    ];
+
+    /// The general purpose register encoded as `index` in an instruction.
+    pub fn general(index: u8) -> Option<RegId> {
+        RegId::GENERAL.get(index as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegId::R0 => "r0",
+            RegId::R1 => "r1",
+            RegId::R2 => "r2",
+            RegId::R3 => "r3",
+            RegId::R4 => "r4",
+            RegId::R5 => "r5",
+            RegId::R6 => "r6",
+            RegId::R7 => "r7",
+            RegId::PC => "pc",
+            RegId::SP => "sp",
+            RegId::BP => "bp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    general: [u16; GENERAL_REGISTERS],
 
     pc: u16,
     sp: u16,
@@ -830,30 +1095,62 @@ struct Registers {
 impl Default for Registers {
     fn default() -> Self {
         Registers {
-            r0: 0,
-            r1: 0,
-            r2: 0,
-            r3: 0,
-            r4: 0,
-            r5: 0,
-            r6: 0,
-            r7: 0,
+            general: [0; GENERAL_REGISTERS],
             pc: 0,
-            sp: MEMORY_SIZE as u16, // Initialize stack pointer to the end of memory
+            sp: DEFAULT_MEMORY_SIZE as u16, // Initialize stack pointer to the end of memory
             bp: 0,
         }
     }
 }
 
 impl Registers {
-    fn len(&self) -> usize {
-        7
+    pub fn get(&self, id: RegId) -> u16 {
+        self[id]
+    }
+
+    pub fn set(&mut self, id: RegId, value: u16) {
+        self[id] = value;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn bp(&self) -> u16 {
+        self.bp
+    }
+}
+
+impl Index<RegId> for Registers {
+    type Output = u16;
+
+    fn index(&self, id: RegId) -> &u16 {
+        match id {
+            RegId::PC => &self.pc,
+            RegId::SP => &self.sp,
+            RegId::BP => &self.bp,
+            general => &self.general[general as usize],
+        }
+    }
+}
+
+impl IndexMut<RegId> for Registers {
+    fn index_mut(&mut self, id: RegId) -> &mut u16 {
+        match id {
+            RegId::PC => &mut self.pc,
+            RegId::SP => &mut self.sp,
+            RegId::BP => &mut self.bp,
+            general => &mut self.general[general as usize],
+        }
     }
-    
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Flags {
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
     zero: bool,
     negative: bool,
     carry: bool,
@@ -861,20 +1158,64 @@ struct Flags {
     interrupt: bool
 }
 
-impl Default for Flags {
-    fn default() -> Self {
+impl Flags {
+    pub fn zero(&self) -> bool {
+        self.zero
+    }
+
+    pub fn negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn carry(&self) -> bool {
+        self.carry
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    /// Packs the flags as Z, N, C, V and I from bit 0 up, the word pushed on interrupt entry.
+    pub fn bits(&self) -> u8 {
+        self.zero as u8
+            | (self.negative as u8) << 1
+            | (self.carry as u8) << 2
+            | (self.overflow as u8) << 3
+            | (self.interrupt as u8) << 4
+    }
+
+    fn from_bits(bits: u8) -> Flags {
         Flags {
-            zero: false,
-            negative: false,
-            carry: false,
-            overflow: false,
-            interrupt: false
+            zero: bits & 1 != 0,
+            negative: bits & (1 << 1) != 0,
+            carry: bits & (1 << 2) != 0,
+            overflow: bits & (1 << 3) != 0,
+            interrupt: bits & (1 << 4) != 0,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Opcode {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Opcode {
+    // The second byte packs the addressing mode (bits 7-6), reg1 (bits 5-3) and
+    // reg2 (bits 2-0). Register mode takes the source from reg2, Immediate and
+    // Memory modes from a data byte after it:
+    //
+    //   ADD r1, r2       10 4A
+    //   ADD r1, 0x12     10 08 12
+    //
+    // Setting the top bit (0x80) of the opcode byte marks the wide form, whose
+    // data is a little-endian 16-bit word instead of a single byte. Every opcode
+    // taking a source operand has a wide form, valid in the Immediate and Memory
+    // modes:
+    //
+    //   ADD r1, 0x1234   90 08 34 12
+
     // Data Movement (0000)
     LOAD = 0x00,    // 0000 0000
     STORE = 0x01,   // 0000 0001
@@ -888,6 +1229,14 @@ enum Opcode {
     DIV = 0x13,     // 0001 0011
     INC = 0x14,     // 0001 0100
     DEC = 0x15,     // 0001 0101
+    CMP = 0x16,     // 0001 0110  SUB without writing reg1
+    ADC = 0x17,     // 0001 0111  ADD plus the carry
+    SBC = 0x18,     // 0001 1000  SUB minus the carry (borrow)
+    MULX = 0x19,    // 0001 1001  MUL r1, r2 keeping the high word in r2; registers only
+    IMUL = 0x1A,    // 0001 1010  signed MUL
+    IDIV = 0x1B,    // 0001 1011  signed DIV
+    MOD = 0x1C,     // 0001 1100  unsigned remainder
+    IMOD = 0x1D,    // 0001 1101  signed remainder
 
     // Logic (0010)
     AND = 0x20,     // 0010 0000
@@ -896,14 +1245,30 @@ enum Opcode {
     NOT = 0x23,     // 0010 0011
     SHL = 0x24,     // 0010 0100
     SHR = 0x25,     // 0010 0101
-
-    // Control Flow (0011)
+    TEST = 0x26,    // 0010 0110  AND without writing reg1
+    RCL = 0x27,     // 0010 0111  rotate left through the carry
+    RCR = 0x28,     // 0010 1000  rotate right through the carry
+    SAR = 0x29,     // 0010 1001  arithmetic shift right
+
+    // Control Flow (0011). Conditional jumps test the flags; after `CMP a, b`
+    // JL, JGE, JG and JLE compare a and b as signed numbers, and JC (JB), JNC
+    // (JAE) and JA as unsigned ones.
     JMP = 0x30,     // 0011 0000
-    JZ = 0x31,      // 0011 0001
-    JNZ = 0x32,     // 0011 0010
-    JC = 0x33,      // 0011 0011
+    JZ = 0x31,      // 0011 0001  Z
+    JNZ = 0x32,     // 0011 0010  !Z
+    JC = 0x33,      // 0011 0011  C
     CALL = 0x34,    // 0011 0100
     RET = 0x35,     // 0011 0101
+    JN = 0x36,      // 0011 0110  N
+    JNN = 0x37,     // 0011 0111  !N
+    JO = 0x38,      // 0011 1000  V
+    JNO = 0x39,     // 0011 1001  !V
+    JNC = 0x3A,     // 0011 1010  !C
+    JL = 0x3B,      // 0011 1011  N != V
+    JGE = 0x3C,     // 0011 1100  N == V
+    JG = 0x3D,      // 0011 1101  !Z and N == V
+    JLE = 0x3E,     // 0011 1110  Z or N != V
+    JA = 0x3F,      // 0011 1111  !C and !Z
 
     // Stack (0100)
     PUSH = 0x40,    // 0100 0000
@@ -911,11 +1276,14 @@ enum Opcode {
 
     // System (0111)
     NOP = 0x70,     // 0111 0000
+    EI = 0x71,      // 0111 0001
+    DI = 0x72,      // 0111 0010
+    IRET = 0x73,    // 0111 0011
     HALT = 0x7F,    // 0111 1111
 }
 
 impl Opcode {
-    fn from_byte(byte: u8) -> Option<Opcode> {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
         match byte {
             0x00 => Some(Opcode::LOAD),
             0x01 => Some(Opcode::STORE),
@@ -927,26 +1295,138 @@ impl Opcode {
             0x13 => Some(Opcode::DIV),
             0x14 => Some(Opcode::INC),
             0x15 => Some(Opcode::DEC),
+            0x16 => Some(Opcode::CMP),
+            0x17 => Some(Opcode::ADC),
+            0x18 => Some(Opcode::SBC),
+            0x19 => Some(Opcode::MULX),
+            0x1A => Some(Opcode::IMUL),
+            0x1B => Some(Opcode::IDIV),
+            0x1C => Some(Opcode::MOD),
+            0x1D => Some(Opcode::IMOD),
             0x20 => Some(Opcode::AND),
             0x21 => Some(Opcode::OR),
             0x22 => Some(Opcode::XOR),
             0x23 => Some(Opcode::NOT),
             0x24 => Some(Opcode::SHL),
             0x25 => Some(Opcode::SHR),
+            0x26 => Some(Opcode::TEST),
+            0x27 => Some(Opcode::RCL),
+            0x28 => Some(Opcode::RCR),
+            0x29 => Some(Opcode::SAR),
             0x30 => Some(Opcode::JMP),
             0x31 => Some(Opcode::JZ),
             0x32 => Some(Opcode::JNZ),
             0x33 => Some(Opcode::JC),
             0x34 => Some(Opcode::CALL),
             0x35 => Some(Opcode::RET),
+            0x36 => Some(Opcode::JN),
+            0x37 => Some(Opcode::JNN),
+            0x38 => Some(Opcode::JO),
+            0x39 => Some(Opcode::JNO),
+            0x3A => Some(Opcode::JNC),
+            0x3B => Some(Opcode::JL),
+            0x3C => Some(Opcode::JGE),
+            0x3D => Some(Opcode::JG),
+            0x3E => Some(Opcode::JLE),
+            0x3F => Some(Opcode::JA),
             0x40 => Some(Opcode::PUSH),
             0x41 => Some(Opcode::POP),
             0x70 => Some(Opcode::NOP),
+            0x71 => Some(Opcode::EI),
+            0x72 => Some(Opcode::DI),
+            0x73 => Some(Opcode::IRET),
             0x7F => Some(Opcode::HALT),
             _ => None
         }
     }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opcode::LOAD => "LOAD",
+            Opcode::STORE => "STORE",
+            Opcode::MOV => "MOV",
+            Opcode::SWAP => "SWAP",
+            Opcode::ADD => "ADD",
+            Opcode::SUB => "SUB",
+            Opcode::MUL => "MUL",
+            Opcode::DIV => "DIV",
+            Opcode::INC => "INC",
+            Opcode::DEC => "DEC",
+            Opcode::CMP => "CMP",
+            Opcode::ADC => "ADC",
+            Opcode::SBC => "SBC",
+            Opcode::MULX => "MULX",
+            Opcode::IMUL => "IMUL",
+            Opcode::IDIV => "IDIV",
+            Opcode::MOD => "MOD",
+            Opcode::IMOD => "IMOD",
+            Opcode::AND => "AND",
+            Opcode::OR => "OR",
+            Opcode::XOR => "XOR",
+            Opcode::NOT => "NOT",
+            Opcode::SHL => "SHL",
+            Opcode::SHR => "SHR",
+            Opcode::TEST => "TEST",
+            Opcode::RCL => "RCL",
+            Opcode::RCR => "RCR",
+            Opcode::SAR => "SAR",
+            Opcode::JMP => "JMP",
+            Opcode::JZ => "JZ",
+            Opcode::JNZ => "JNZ",
+            Opcode::JC => "JC",
+            Opcode::CALL => "CALL",
+            Opcode::RET => "RET",
+            Opcode::JN => "JN",
+            Opcode::JNN => "JNN",
+            Opcode::JO => "JO",
+            Opcode::JNO => "JNO",
+            Opcode::JNC => "JNC",
+            Opcode::JL => "JL",
+            Opcode::JGE => "JGE",
+            Opcode::JG => "JG",
+            Opcode::JLE => "JLE",
+            Opcode::JA => "JA",
+            Opcode::PUSH => "PUSH",
+            Opcode::POP => "POP",
+            Opcode::NOP => "NOP",
+            Opcode::EI => "EI",
+            Opcode::DI => "DI",
+            Opcode::IRET => "IRET",
+            Opcode::HALT => "HALT",
+        }
+    }
+
+    /// Whether the opcode may set the wide flag to carry a 16-bit address or value.
+    pub fn has_wide_form(&self) -> bool {
+        matches!(
+            self,
+            Opcode::LOAD | Opcode::STORE | Opcode::MOV | Opcode::SWAP
+                | Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::CMP
+                | Opcode::ADC | Opcode::SBC
+                | Opcode::IMUL | Opcode::IDIV | Opcode::MOD | Opcode::IMOD
+                | Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::TEST
+                | Opcode::RCL | Opcode::RCR | Opcode::SAR
+                | Opcode::JMP | Opcode::JZ | Opcode::JNZ | Opcode::JC | Opcode::CALL | Opcode::PUSH
+                | Opcode::JN | Opcode::JNN | Opcode::JO | Opcode::JNO | Opcode::JNC
+                | Opcode::JL | Opcode::JGE | Opcode::JG | Opcode::JLE | Opcode::JA
+        )
+    }
+
+    /// Looks up an opcode by mnemonic, ignoring case. JB and JAE are accepted
+    /// as the unsigned names of JC and JNC.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        if mnemonic.eq_ignore_ascii_case("JB") {
+            return Some(Opcode::JC);
+        }
+        if mnemonic.eq_ignore_ascii_case("JAE") {
+            return Some(Opcode::JNC);
+        }
+        (0..=u8::MAX)
+            .filter_map(Opcode::from_byte)
+            .find(|opcode| opcode.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
\ No newline at end of file
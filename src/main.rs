@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use RustyCpu::assembler::{assemble, assemble_program};
+use RustyCpu::debugger::Debugger;
+use RustyCpu::disassembler::listing;
+use RustyCpu::gdb::GdbStub;
+use RustyCpu::trace::{TraceFormat, WriteTracer};
+use RustyCpu::{Cpu, HaltReason, InterruptController, MemoryMap, Snapshot, Timer, Uart, MAX_MEMORY_SIZE};
+
+// The console UART and the timer take a 16-byte window each; everything else is RAM.
+const UART_BASE: u16 = 0xF000;
+const TIMER_BASE: u16 = 0xF010;
+const DEVICE_WINDOW: usize = 16;
+const TIMER_IRQ: u8 = 0;
+
+const DEFAULT_GDB_PORT: u16 = 1234;
+// Steps debug and gdb sessions can reverse over.
+const DEBUG_HISTORY: usize = 10_000;
+
+const USAGE: &str = "usage: rustycpu <command> [args]
+
+commands:
+  run <image> [--trace <format>] [--trace-file <path>] [--max-steps <n>]
+      [--load-snapshot <path>] [--save-snapshot <path>]
+                          run a memory image until HALT, tracing each instruction
+                          as text, json or binary to stderr or the trace file, and
+                          giving up after n instructions if given. a loaded
+                          snapshot replaces the image's initial state; a saved one
+                          records the state the run stopped in
+  asm <src> -o <image>    assemble a source file into a memory image
+  disasm <image>          print an assembler listing of a memory image
+  debug <image>           debug a memory image with commands read from stdin
+                          (break, delete, watch, step, next, finish, continue,
+                          reverse-step, reverse-continue, regs, flags,
+                          x/<n> <addr>, disas [addr], quit)
+  gdb <image> [port]      serve one GDB remote connection on localhost, port 1234
+                          unless given
+
+run, debug and gdb assemble images ending in .asm first, and debug then accepts labels
+as addresses
+
+images run in 64 KiB of RAM with a console UART at 0xF000 (data) and 0xF001
+(status); UART output goes to stdout, and for run stdin feeds UART input as it arrives.
+an interval timer at 0xF010 (reload word, counter word, control) raises IRQ 0
+
+exit status: 0 when the CPU halts, 1 when it faults or input is invalid, 2 on usage errors,
+3 when the step limit runs out";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("run") => run(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("disasm") => disasm(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb(&args[1..]),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(ExitCode::SUCCESS)
+        }
+        _ => Err(usage()),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("rustycpu: {}", error.message);
+            ExitCode::from(error.code)
+        }
+    }
+}
+
+struct CliError {
+    message: String,
+    code: u8,
+}
+
+fn usage() -> CliError {
+    CliError { message: USAGE.to_string(), code: 2 }
+}
+
+fn failure(message: String) -> CliError {
+    CliError { message, code: 1 }
+}
+
+fn single_path(args: &[String]) -> Result<&str, CliError> {
+    match args {
+        [path] => Ok(path),
+        _ => Err(usage()),
+    }
+}
+
+// Reads an image, assembling `.asm` sources and keeping their labels.
+fn read_image(path: &str) -> Result<(Vec<u8>, BTreeMap<String, u16>), CliError> {
+    if path.ends_with(".asm") {
+        let source = fs::read_to_string(path).map_err(|e| failure(format!("{}: {}", path, e)))?;
+        let program = assemble_program(&source).map_err(|e| failure(format!("{}:{}", path, e)))?;
+        return Ok((program.image, program.labels));
+    }
+
+    let image = fs::read(path).map_err(|e| failure(format!("{}: {}", path, e)))?;
+    Ok((image, BTreeMap::new()))
+}
+
+fn read_snapshot(path: &str) -> Result<Snapshot, CliError> {
+    let bytes = fs::read(path).map_err(|e| failure(format!("{}: {}", path, e)))?;
+    Snapshot::from_bytes(&bytes).map_err(|e| failure(format!("{}: {}", path, e)))
+}
+
+// Builds the board around `image`, then restores `snapshot` over it if given.
+// With `uart_stdin`, stdin is received as UART input after any restored input.
+fn load_image(
+    path: &str,
+    image: &[u8],
+    snapshot: Option<(&str, &Snapshot)>,
+    uart_stdin: bool,
+) -> Result<Cpu<MemoryMap>, CliError> {
+    let mut uart = Uart::with_sink(io::stdout());
+    if uart_stdin {
+        uart = uart.with_source(stdin_source());
+    }
+
+    let interrupts = InterruptController::new();
+    let timer = Timer::new(interrupts.clone(), TIMER_IRQ);
+
+    let above_devices = TIMER_BASE as usize + DEVICE_WINDOW;
+    let board = MemoryMap::new(MAX_MEMORY_SIZE)
+        .ram(0, UART_BASE as usize)
+        .device(UART_BASE, DEVICE_WINDOW, Box::new(uart))
+        .device(TIMER_BASE, DEVICE_WINDOW, Box::new(timer))
+        .ram(above_devices as u16, MAX_MEMORY_SIZE - above_devices);
+
+    let mut cpu = Cpu::with_bus(board).with_interrupts(interrupts);
+    cpu.load_program(image).map_err(|e| failure(format!("{}: {}", path, e)))?;
+    if let Some((snapshot_path, snapshot)) = snapshot {
+        cpu.restore(snapshot).map_err(|e| failure(format!("{}: {}", snapshot_path, e)))?;
+    }
+    Ok(cpu)
+}
+
+// Reads stdin on its own thread so the guest sees input as it arrives rather
+// than after end of file. A read error ends the input like end of file does.
+fn stdin_source() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 1024];
+        while let Ok(count @ 1..) = io::stdin().read(&mut buffer) {
+            if sender.send(buffer[..count].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn exit_code(reason: HaltReason) -> ExitCode {
+    match reason {
+        HaltReason::Halted => ExitCode::SUCCESS,
+        HaltReason::Fault(e) => {
+            eprintln!("rustycpu: fault: {}", e);
+            ExitCode::FAILURE
+        }
+        HaltReason::BudgetExhausted { pc } => {
+            eprintln!("rustycpu: step limit reached at pc 0x{:04X}", pc);
+            ExitCode::from(3)
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode, CliError> {
+    let (path, options) = args.split_first().ok_or_else(usage)?;
+    let mut format = None;
+    let mut trace_path = None;
+    let mut max_steps = None;
+    let mut load_path = None;
+    let mut save_path = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(usage)?;
+        match option.as_str() {
+            "--trace" => format = Some(TraceFormat::from_name(value).ok_or_else(usage)?),
+            "--trace-file" => trace_path = Some(value),
+            "--max-steps" => max_steps = Some(value.parse().map_err(|_| usage())?),
+            "--load-snapshot" => load_path = Some(value.as_str()),
+            "--save-snapshot" => save_path = Some(value),
+            _ => return Err(usage()),
+        }
+    }
+
+    let (image, _) = read_image(path)?;
+    let snapshot = load_path.map(read_snapshot).transpose()?;
+    let mut cpu = load_image(path, &image, load_path.zip(snapshot.as_ref()), true)?;
+
+    match (format, trace_path) {
+        (Some(format), Some(trace_path)) => {
+            let file = fs::File::create(trace_path).map_err(|e| failure(format!("{}: {}", trace_path, e)))?;
+            cpu.set_tracer(Box::new(WriteTracer::new(io::BufWriter::new(file), format)));
+        }
+        (Some(format), None) => cpu.set_tracer(Box::new(WriteTracer::new(io::stderr(), format))),
+        (None, Some(_)) => return Err(usage()),
+        (None, None) => {}
+    }
+
+    let reason = match max_steps {
+        Some(max_steps) => cpu.run_with_limit(max_steps),
+        None => cpu.run(),
+    };
+    if let Some(mut tracer) = cpu.take_tracer() {
+        tracer.flush().map_err(|e| failure(format!("trace: {}", e)))?;
+    }
+    if let Some(save_path) = save_path {
+        fs::write(save_path, cpu.snapshot().to_bytes()).map_err(|e| failure(format!("{}: {}", save_path, e)))?;
+    }
+    Ok(exit_code(reason))
+}
+
+fn asm(args: &[String]) -> Result<ExitCode, CliError> {
+    let (source_path, image_path) = match args {
+        [source, flag, image] if flag == "-o" => (source, image),
+        [flag, image, source] if flag == "-o" => (source, image),
+        _ => return Err(usage()),
+    };
+
+    let source = fs::read_to_string(source_path).map_err(|e| failure(format!("{}: {}", source_path, e)))?;
+    let image = assemble(&source).map_err(|e| failure(format!("{}:{}", source_path, e)))?;
+    fs::write(image_path, image).map_err(|e| failure(format!("{}: {}", image_path, e)))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn disasm(args: &[String]) -> Result<ExitCode, CliError> {
+    let path = single_path(args)?;
+    let image = fs::read(path).map_err(|e| failure(format!("{}: {}", path, e)))?;
+    print!("{}", listing(&image));
+    Ok(ExitCode::SUCCESS)
+}
+
+fn debug(args: &[String]) -> Result<ExitCode, CliError> {
+    let path = single_path(args)?;
+    let (image, labels) = read_image(path)?;
+    let mut debugger = Debugger::new(load_image(path, &image, None, false)?)
+        .with_labels(labels)
+        .with_history(DEBUG_HISTORY);
+
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        if interactive {
+            print!("(rustycpu) ");
+            io::stdout().flush().map_err(|e| failure(format!("stdout: {}", e)))?;
+        }
+
+        let line = match lines.next() {
+            Some(line) => line.map_err(|e| failure(format!("stdin: {}", e)))?,
+            None => return Ok(ExitCode::SUCCESS),
+        };
+        if matches!(line.trim(), "quit" | "q") {
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        match debugger.execute(&line) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+fn gdb(args: &[String]) -> Result<ExitCode, CliError> {
+    let (path, port) = match args {
+        [path] => (path, DEFAULT_GDB_PORT),
+        [path, port] => (path, port.parse().map_err(|_| usage())?),
+        _ => return Err(usage()),
+    };
+
+    let (image, labels) = read_image(path)?;
+    let debugger = Debugger::new(load_image(path, &image, None, false)?)
+        .with_labels(labels)
+        .with_history(DEBUG_HISTORY);
+    eprintln!("rustycpu: waiting for gdb on 127.0.0.1:{}", port);
+    GdbStub::new(debugger)
+        .listen(("127.0.0.1", port))
+        .map_err(|e| failure(format!("gdb: {}", e)))?;
+    Ok(ExitCode::SUCCESS)
+}
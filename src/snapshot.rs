@@ -0,0 +1,193 @@
+use std::fmt;
+
+use crate::{Flags, Instruction, RegId, Registers};
+
+/// Version written by [`Snapshot::to_bytes`] and the only one it reads back.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 4] = b"RCPU";
+
+/// The complete state of a [`Cpu`](crate::Cpu) and its bus at one point in time,
+/// taken with [`Cpu::snapshot`](crate::Cpu::snapshot).
+///
+/// The serialized form is little-endian throughout:
+///
+/// ```text
+/// "RCPU", u16 version
+/// 11 x u16 registers in RegId::ALL order
+/// u8 flags (as Flags::bits), u8 running, u64 cycles
+/// u8 pending IRQs, u8 masked IRQs, u16 vector base
+/// u8 n, n bytes encoding the current instruction (0 for none)
+/// u32 n, n bytes of bus state (see Bus::save_state)
+/// ```
+///
+/// The cost table and tracer are configuration rather than state and are not saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: Registers,
+    pub flags: Flags,
+    pub running: bool,
+    pub current_instruction: Option<Instruction>,
+    pub cycles: u64,
+    pub pending_irqs: u8,
+    pub masked_irqs: u8,
+    pub vector_base: u16,
+    /// RAM contents and device state, as saved by the bus.
+    pub bus: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        for id in RegId::ALL {
+            bytes.extend_from_slice(&self.registers[id].to_le_bytes());
+        }
+        bytes.push(self.flags.bits());
+        bytes.push(self.running as u8);
+        bytes.extend_from_slice(&self.cycles.to_le_bytes());
+        bytes.push(self.pending_irqs);
+        bytes.push(self.masked_irqs);
+        bytes.extend_from_slice(&self.vector_base.to_le_bytes());
+
+        let instruction = self.current_instruction.as_ref().map_or(Vec::new(), Instruction::encode);
+        bytes.push(instruction.len() as u8);
+        bytes.extend_from_slice(&instruction);
+
+        write_chunk(&mut bytes, &self.bus);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader::new(bytes);
+        if reader.bytes(MAGIC.len()).map_err(|_| SnapshotError::NotASnapshot)? != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut registers = Registers::default();
+        for id in RegId::ALL {
+            registers[id] = reader.u16()?;
+        }
+        let flags = Flags::from_bits(reader.u8()?);
+        let running = reader.u8()? != 0;
+        let cycles = reader.u64()?;
+        let pending_irqs = reader.u8()?;
+        let masked_irqs = reader.u8()?;
+        let vector_base = reader.u16()?;
+
+        let length = reader.u8()? as usize;
+        let encoding = reader.bytes(length)?;
+        let current_instruction = match encoding {
+            [] => None,
+            _ => {
+                let mut encoding = encoding.iter().copied();
+                match Instruction::decode(0, || encoding.next()) {
+                    Ok(instruction) if encoding.next().is_none() => Some(instruction),
+                    _ => return Err(SnapshotError::Invalid("current instruction")),
+                }
+            }
+        };
+
+        let bus = reader.chunk()?.to_vec();
+        reader.finish()?;
+
+        Ok(Snapshot {
+            registers,
+            flags,
+            running,
+            current_instruction,
+            cycles,
+            pending_irqs,
+            masked_irqs,
+            vector_base,
+            bus,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The data does not start with the snapshot magic.
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    Truncated,
+    /// A field holds a value no snapshot would contain.
+    Invalid(&'static str),
+    /// The snapshot was taken of a machine laid out differently from this one.
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "truncated snapshot"),
+            SnapshotError::Invalid(what) => write!(f, "invalid {} in snapshot", what),
+            SnapshotError::Mismatch(what) => write!(f, "snapshot {} does not match this machine", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Appends `data` prefixed with its length as a little-endian u32.
+pub(crate) fn write_chunk(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+}
+
+/// Reads little-endian fields from saved state, failing with
+/// [`SnapshotError::Truncated`] when the data runs out.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub(crate) fn bytes(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if length > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads data written by [`write_chunk`].
+    pub(crate) fn chunk(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+
+    /// Fails unless every byte has been read.
+    pub(crate) fn finish(&self) -> Result<(), SnapshotError> {
+        match self.bytes {
+            [] => Ok(()),
+            _ => Err(SnapshotError::Invalid("trailing data")),
+        }
+    }
+}
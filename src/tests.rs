@@ -1,6 +1,21 @@
 #[cfg(test)]
 mod tests {
-    use crate::Cpu;
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use crate::assembler::{assemble, assemble_program, AsmErrorKind};
+    use crate::debugger::{CommandError, Debugger, Stop};
+    use crate::disassembler::{disassemble, listing};
+    use crate::gdb::GdbStub;
+    use crate::trace::{TraceFormat, WriteTracer};
+    use crate::{
+        AddressingMode, Bus, Cpu, CpuError, CycleCosts, Device, HaltReason, Instruction, InterruptController, MemoryMap,
+        MemoryWrite, Opcode, RegId, RegisterChange, RomWrites, Snapshot, SnapshotError, Timer, Uart, TIMER_ENABLE,
+    };
 
     #[test]
     fn test_load_immediate() {
@@ -10,20 +25,20 @@ mod tests {
         cpu.memory.data[2] = 0b0000_0001;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 1);
+        assert_eq!(cpu.registers[RegId::R0], 1);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_store_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 10;
+        cpu.registers[RegId::R0] = 10;
         cpu.memory.data[0] = 0b0000_0001; // store r0, 10
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_1010;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.memory.read(10), 10);
+        assert_eq!(cpu.memory.read(10), Ok(10));
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
@@ -35,174 +50,174 @@ mod tests {
         cpu.memory.data[2] = 0b0000_0011;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r1, 3);
+        assert_eq!(cpu.registers[RegId::R1], 3);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_swap_register() {
         let mut cpu = Cpu::default();
-        cpu.registers.r1 = 5;
-        cpu.registers.r2 = 10;
+        cpu.registers[RegId::R1] = 5;
+        cpu.registers[RegId::R2] = 10;
         cpu.memory.data[0] = 0b0000_0011; // swap r1, r2
         cpu.memory.data[1] = 0b0100_1010;
         cpu.memory.data[2] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r1, 10);
-        assert_eq!(cpu.registers.r2, 5);
+        assert_eq!(cpu.registers[RegId::R1], 10);
+        assert_eq!(cpu.registers[RegId::R2], 5);
         assert_eq!(cpu.registers.pc, 3 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_add_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 5;
+        cpu.registers[RegId::R0] = 5;
         cpu.memory.data[0] = 0b0001_0000; // add r0, 3
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0011;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 8);
+        assert_eq!(cpu.registers[RegId::R0], 8);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_sub_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 5;
+        cpu.registers[RegId::R0] = 5;
         cpu.memory.data[0] = 0b0001_0001; // sub r0, 3
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0011;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 2);
+        assert_eq!(cpu.registers[RegId::R0], 2);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_mul_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 5;
+        cpu.registers[RegId::R0] = 5;
         cpu.memory.data[0] = 0b0001_0010; // mul r0, 3
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0011;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 15);
+        assert_eq!(cpu.registers[RegId::R0], 15);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_div_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 6;
+        cpu.registers[RegId::R0] = 6;
         cpu.memory.data[0] = 0b0001_0011; // div r0, 3
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0011;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 2);
+        assert_eq!(cpu.registers[RegId::R0], 2);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_inc_register() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 5;
+        cpu.registers[RegId::R0] = 5;
         cpu.memory.data[0] = 0b0001_0100; // inc r0
         cpu.memory.data[1] = 0b0100_0000;
         cpu.memory.data[2] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 6);
+        assert_eq!(cpu.registers[RegId::R0], 6);
         assert_eq!(cpu.registers.pc, 3 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_dec_register() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 5;
+        cpu.registers[RegId::R0] = 5;
         cpu.memory.data[0] = 0b0001_0101; // dec r0
         cpu.memory.data[1] = 0b0100_0000;
         cpu.memory.data[2] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 4);
+        assert_eq!(cpu.registers[RegId::R0], 4);
         assert_eq!(cpu.registers.pc, 3 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_and_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b1010_1010;
+        cpu.registers[RegId::R0] = 0b1010_1010;
         cpu.memory.data[0] = 0b0010_0000; // and r0, 0b1100_1100
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b1100_1100;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 0b1000_1000);
+        assert_eq!(cpu.registers[RegId::R0], 0b1000_1000);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_or_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b1010_1010;
+        cpu.registers[RegId::R0] = 0b1010_1010;
         cpu.memory.data[0] = 0b0010_0001; // or r0, 0b1100_1100
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b1100_1100;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 0b1110_1110);
+        assert_eq!(cpu.registers[RegId::R0], 0b1110_1110);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_xor_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b1010_1010;
+        cpu.registers[RegId::R0] = 0b1010_1010;
         cpu.memory.data[0] = 0b0010_0010; // xor r0, 0b1100_1100
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b1100_1100;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 0b0110_0110);
+        assert_eq!(cpu.registers[RegId::R0], 0b0110_0110);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_not_register() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b1010_1010;
+        cpu.registers[RegId::R0] = 0b1010_1010;
         cpu.memory.data[0] = 0b0010_0011; // not r0
         cpu.memory.data[1] = 0b0100_0000;
         cpu.memory.data[2] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, !0b1010_1010);
+        assert_eq!(cpu.registers[RegId::R0], !0b1010_1010);
         assert_eq!(cpu.registers.pc, 3 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_shl_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b0001_0000;
+        cpu.registers[RegId::R0] = 0b0001_0000;
         cpu.memory.data[0] = 0b0010_0100; // shl r0, 2
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0010;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 0b0100_0000);
+        assert_eq!(cpu.registers[RegId::R0], 0b0100_0000);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
     #[test]
     fn test_shr_immediate() {
         let mut cpu = Cpu::default();
-        cpu.registers.r0 = 0b0100_0000;
+        cpu.registers[RegId::R0] = 0b0100_0000;
         cpu.memory.data[0] = 0b0010_0101; // shr r0, 2
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0010;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 0b0001_0000);
+        assert_eq!(cpu.registers[RegId::R0], 0b0001_0000);
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
@@ -263,23 +278,25 @@ mod tests {
         cpu.run();
         cpu.debug();
         assert_eq!(cpu.registers.pc, 6 + 2); // Adjust for HALT
-        assert_eq!(cpu.memory.read(cpu.registers.sp), 3);
-        assert_eq!(cpu.memory.read(cpu.registers.sp.wrapping_add(1)), 0);
+        assert_eq!(cpu.memory.read(cpu.registers.sp), Ok(3));
+        assert_eq!(cpu.memory.read(cpu.registers.sp.wrapping_add(1)), Ok(0));
 
         
     }
 
     #[test]
     fn test_ret() {
-        // TODO not working
         let mut cpu = Cpu::default();
         cpu.memory.data[0] = 0b0011_0100; // call 5
         cpu.memory.data[1] = 0b0000_0000;
         cpu.memory.data[2] = 0b0000_0101;
+        cpu.memory.data[3] = 0b0111_1111; // halt
+        cpu.memory.data[4] = 0b0100_0000;
         cpu.memory.data[5] = 0b0011_0101; // ret
-        cpu.memory.data[7] = 0b0111_1111; // halt
+        cpu.memory.data[6] = 0b0100_0000;
         cpu.run();
-        assert_eq!(cpu.registers.pc, 5 + 2+1); // Adjust for HALT
+        assert_eq!(cpu.registers.pc, 3 + 2); // Adjust for HALT
+        assert_eq!(cpu.registers.sp, 256);
     }
 
     #[test]
@@ -290,7 +307,7 @@ mod tests {
         cpu.memory.data[2] = 0b0000_1010;
         cpu.memory.data[3] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.memory.read(cpu.registers.sp), 10);
+        assert_eq!(cpu.memory.read(cpu.registers.sp), Ok(10));
         assert_eq!(cpu.registers.pc, 4 + 2); // Adjust for HALT
     }
 
@@ -304,7 +321,7 @@ mod tests {
         cpu.memory.data[4] = 0b0100_0000;
         cpu.memory.data[5] = 0b0111_1111; // halt
         cpu.run();
-        assert_eq!(cpu.registers.r0, 10);
+        assert_eq!(cpu.registers[RegId::R0], 10);
         assert_eq!(cpu.registers.pc, 6 + 2); // Adjust for HALT
     }
 
@@ -318,4 +335,1241 @@ mod tests {
         cpu.run();
         assert_eq!(cpu.registers.pc, 2+2+1); // Adjust for HALT
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_assemble_addressing_modes() {
+        let image = assemble(
+            "MOV r1, 3\n\
+             ADD r1, r2\n\
+             LOAD r0, [r3]\n\
+             LOAD r0, [0x20]\n\
+             INC r0\n\
+             HALT",
+        )
+        .unwrap();
+        assert_eq!(
+            image,
+            vec![
+                0x02, 0b0000_1000, 3,
+                0x10, 0b0100_1010,
+                0x00, 0b1000_0011,
+                0x00, 0b1100_0000, 0x20,
+                0x14, 0b0100_0000,
+                0x7F, 0b0100_0000,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_labels_and_literals() {
+        let image = assemble(
+            "start:  jmp end      ; forward reference\n\
+             data:   push 0b1010_1010\n\
+                     push -1\n\
+             end:    call data\n\
+                     jz start",
+        )
+        .unwrap();
+        assert_eq!(
+            image,
+            vec![0x30, 0x00, 10, 0x40, 0x00, 0xAA, 0xC0, 0x00, 0xFF, 0xFF, 0x34, 0x00, 3, 0x31, 0x00, 0]
+        );
+    }
+
+    #[test]
+    fn test_assembled_program_runs() {
+        let image = assemble(
+            "    mov r0, 5\n\
+             loop:\n\
+                 dec r0\n\
+                 jnz loop\n\
+                 halt",
+        )
+        .unwrap();
+        let mut cpu = Cpu::default();
+        cpu.memory.data[..image.len()].copy_from_slice(&image);
+        cpu.run();
+        assert_eq!(cpu.registers[RegId::R0], 0);
+        assert_eq!(cpu.registers.pc, image.len() as u16);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = assemble("mov r0, 1\n  frob r1").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+        assert_eq!(error.kind, AsmErrorKind::UnknownMnemonic("frob".to_string()));
+
+        let error = assemble("add r0, 0x10000").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 9));
+        assert_eq!(error.kind, AsmErrorKind::ImmediateOutOfRange(0x10000));
+
+        let error = assemble("jmp nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 5));
+        assert_eq!(error.kind, AsmErrorKind::UndefinedLabel("nowhere".to_string()));
+
+        let error = assemble("inc 5").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::InvalidOperand("5".to_string()));
+
+        let error = assemble("swap r1, r8").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 10));
+        assert_eq!(error.kind, AsmErrorKind::InvalidRegister("r8".to_string()));
+
+        let error = assemble("halt r0").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::WrongOperandCount { expected: 0, found: 1 });
+    }
+
+    #[test]
+    fn test_assemble_byte_directive() {
+        let image = assemble("table: .byte 1, 0xFF, -2, table\n.byte end\nend: halt").unwrap();
+        assert_eq!(image, vec![1, 0xFF, 0xFE, 0, 5, 0x7F, 0x40]);
+
+        let error = assemble(".byte r1").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::InvalidOperand("r1".to_string()));
+    }
+
+    #[test]
+    fn test_disassemble_listing() {
+        let image = assemble("mov r1, 3\nadd r1, r2\nload r0, [r3]\nstore r0, [0x20]\ninc r0\njmp 0\nhalt").unwrap();
+        let lines = disassemble(&image);
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec!["MOV r1, 3", "ADD r1, r2", "LOAD r0, [r3]", "STORE r0, [0x20]", "INC r0", "JMP 0", "HALT"]
+        );
+        assert_eq!(lines[1].address, 3);
+        assert_eq!(lines[1].bytes, vec![0x10, 0b0100_1010]);
+        assert_eq!(lines[0].to_string(), "MOV r1, 3               ; 0000: 02 08 03");
+    }
+
+    #[test]
+    fn test_disassemble_marks_undecodable_bytes() {
+        let image = [0x99, 0x5A, 0x7F, 0x00, 0x00, 0x02, 0x08];
+        let text: Vec<String> = disassemble(&image).into_iter().map(|line| line.text).collect();
+        assert_eq!(text, vec![".byte 0x99", ".byte 0x5A", "HALT r0, 0", ".byte 0x02", ".byte 0x08"]);
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let source = "start: mov r1, 200\n\
+                      loop: dec r1\n\
+                      jnz loop\n\
+                      call start\n\
+                      push [r2]\n\
+                      swap r3, r4\n\
+                      .byte 0x99, 0x7F\n\
+                      ret\n\
+                      nop\n\
+                      halt";
+        let image = assemble(source).unwrap();
+        assert_eq!(assemble(&listing(&image)).unwrap(), image);
+
+        // Encodings with unused fields set must survive the round trip too.
+        let raw: Vec<u8> = vec![0x7F, 0x00, 0x00, 0x70, 0x4A, 0x14, 0x0C, 0x30, 0x30, 0x05, 0x03, 0xC8, 0x01, 0xEE];
+        assert_eq!(assemble(&listing(&raw)).unwrap(), raw);
+
+        // MULX only assembles from two distinct registers; anything else is data.
+        let mulx: Vec<u8> = vec![0x19, 0x4A, 0x19, 0x49, 0x19, 0x0A, 0x05, 0x19, 0x8A, 0x19, 0xCA, 0x10];
+        let lines = disassemble(&mulx);
+        assert_eq!(lines[0].text, "MULX r1, r2");
+        assert!(lines[1..].iter().all(|line| line.text.starts_with(".byte")));
+        assert_eq!(assemble(&listing(&mulx)).unwrap(), mulx);
+    }
+
+    #[test]
+    fn test_step_reports_executed_instruction() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r2, 7\nhalt").unwrap()).unwrap();
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(outcome.instruction.opcode(), Opcode::MOV);
+        assert_eq!(outcome.instruction.reg1(), 2);
+        assert_eq!(outcome.instruction.data(), Some(7));
+        assert!(!outcome.halted);
+        assert_eq!(cpu.registers().get(RegId::R2), 7);
+        assert_eq!(cpu.registers().pc(), 3);
+
+        assert!(cpu.step().unwrap().halted);
+        assert!(!cpu.is_running());
+        assert_eq!(cpu.step(), Err(CpuError::Halted));
+    }
+
+    #[test]
+    fn test_run_returns_halt_reason() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r0, 0\nhalt").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert!(cpu.flags().zero());
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&[0x99, 0x40]).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::InvalidOpcode { opcode: 0x99, pc: 0 }));
+    }
+
+    #[test]
+    fn test_load_program_too_large() {
+        let mut cpu = Cpu::new();
+        assert!(cpu.load_program(&[0; 257]).is_err());
+        cpu.memory_mut().write(0x10, 0xAB).unwrap();
+        assert_eq!(cpu.memory().read(0x10), Ok(0xAB));
+    }
+
+    #[test]
+    fn test_divide_by_zero_faults() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r0, 6\ndiv r0, 0\nhalt").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::DivideByZero));
+        assert_eq!(cpu.registers[RegId::R0], 6);
+    }
+
+    #[test]
+    fn test_decode_faults() {
+        let mut cpu = Cpu::new();
+        cpu.memory.data[254] = 0x02;
+        cpu.memory.data[255] = 0x00;
+        cpu.registers.pc = 254;
+        assert_eq!(cpu.step(), Err(CpuError::TruncatedInstruction { pc: 254 }));
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("jmp 255").unwrap()).unwrap();
+        cpu.memory.data[255] = 0x70;
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::TruncatedInstruction { pc: 255 }));
+        assert_eq!(cpu.step(), Err(CpuError::MemoryFault { address: 256 }));
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let mut cpu = Cpu::new();
+        assert_eq!(cpu.memory.read(0x100), Err(CpuError::MemoryFault { address: 0x100 }));
+
+        cpu.registers[RegId::R1] = 0x1234;
+        cpu.load_program(&assemble("store r1, 1").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::MemoryFault { address: 0x1234 }));
+    }
+
+    #[test]
+    fn test_stack_faults() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("ret").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::StackUnderflow));
+
+        let mut cpu = Cpu::new();
+        cpu.registers.sp = 3;
+        cpu.load_program(&assemble("push 1\npush 2").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::StackOverflow));
+        assert_eq!((cpu.registers.sp, cpu.memory.data[1]), (1, 1));
+
+        let mut cpu = Cpu::new();
+        cpu.registers.sp = 1;
+        cpu.load_program(&assemble("call 0").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::StackOverflow));
+        assert_eq!(cpu.registers.sp, 1);
+    }
+
+    // Runs `source` with r1 = 12, r2 = 0x40, memory[0x40] = 9 and memory[0x50] = 4.
+    fn run_with_operands(source: &str) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble(source).unwrap()).unwrap();
+        cpu.registers[RegId::R1] = 12;
+        cpu.registers[RegId::R2] = 0x40;
+        cpu.memory.data[0x40] = 9;
+        cpu.memory.data[0x50] = 4;
+        assert_eq!(cpu.run(), HaltReason::Halted, "{}", source);
+        cpu
+    }
+
+    #[test]
+    fn test_alu_addressing_modes() {
+        let operands = ["3", "r2", "[r2]", "[0x50]"];
+        let cases: [(&str, [u16; 4]); 12] = [
+            ("add", [15, 76, 21, 16]),
+            ("sub", [9, 12u16.wrapping_sub(64), 3, 8]),
+            ("mul", [36, 768, 108, 48]),
+            ("div", [4, 0, 1, 3]),
+            ("and", [0, 0, 8, 4]),
+            ("or", [15, 76, 13, 12]),
+            ("xor", [15, 76, 5, 8]),
+            ("shl", [96, 0, 6144, 192]),
+            ("shr", [1, 0, 0, 0]),
+            ("mov", [3, 64, 9, 4]),
+            ("load", [3, 64, 9, 4]),
+            ("swap", [12, 64, 9, 4]),
+        ];
+
+        for (mnemonic, expected) in cases {
+            for (operand, expected) in operands.iter().zip(expected) {
+                if mnemonic == "swap" && *operand == "3" {
+                    continue;
+                }
+                let source = format!("{} r1, {}\nhalt", mnemonic, operand);
+                let cpu = run_with_operands(&source);
+                assert_eq!(cpu.registers[RegId::R1], expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap_with_memory() {
+        let cpu = run_with_operands("swap r1, [r2]\nswap r1, r2\nhalt");
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R2], cpu.memory.data[0x40]), (0x40, 9, 12));
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("swap r1, 5").unwrap()).unwrap();
+        assert_eq!(
+            cpu.run(),
+            HaltReason::Fault(CpuError::InvalidAddressingMode { opcode: Opcode::SWAP, mode: AddressingMode::Immediate })
+        );
+    }
+
+    #[test]
+    fn test_store_addressing_modes() {
+        assert_eq!(run_with_operands("store r2, 7\nhalt").memory.data[0x40], 7);
+        assert_eq!(run_with_operands("store r2, r1\nhalt").memory.data[0x40], 12);
+        assert_eq!(run_with_operands("store r1, [r2]\nhalt").memory.data[0x40], 12);
+        assert_eq!(run_with_operands("store r1, [0x50]\nhalt").memory.data[0x50], 12);
+    }
+
+    #[test]
+    fn test_jump_addressing_modes() {
+        // Each jump skips `mov r0, 1` and lands on the HALT after it.
+        let cases = [("jmp 6", 6), ("jmp r3", 5), ("jmp [r2]", 5), ("jmp [0x50]", 6), ("call r3", 5), ("jz [r2]", 5)];
+
+        for (jump, target) in cases {
+            let mut cpu = Cpu::new();
+            cpu.load_program(&assemble(&format!("{}\nmov r0, 1\nhalt", jump)).unwrap()).unwrap();
+            cpu.flags.zero = true;
+            cpu.registers[RegId::R2] = 0x40;
+            cpu.registers[RegId::R3] = target;
+            cpu.memory.data[0x40] = target as u8;
+            cpu.memory.data[0x50] = target as u8;
+            assert_eq!(cpu.run(), HaltReason::Halted, "{}", jump);
+            assert_eq!(cpu.registers[RegId::R0], 0, "{}", jump);
+            assert_eq!(cpu.registers.pc, target + 2, "{}", jump);
+        }
+    }
+
+    #[test]
+    fn test_push_pop_addressing_modes() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("push r1\npush [r2]\npush [0x50]\npop r3\npop r4\npop r5\nhalt").unwrap()).unwrap();
+        cpu.registers[RegId::R1] = 0x1234;
+        cpu.registers[RegId::R2] = 0x40;
+        cpu.memory.data[0x40] = 9;
+        cpu.memory.data[0x50] = 4;
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R3], cpu.registers[RegId::R4], cpu.registers[RegId::R5]), (4, 9, 0x1234));
+        assert_eq!(cpu.registers.sp, 256);
+    }
+
+    #[test]
+    fn test_all_general_registers_usable() {
+        let mut source = String::new();
+        for index in 0..8 {
+            source += &format!("mov r{}, {}\n", index, index + 10);
+        }
+        source += "swap r5, r6\nadd r7, r6\nhalt";
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble(&source).unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+
+        let values: Vec<u16> = RegId::GENERAL.iter().map(|id| cpu.registers().get(*id)).collect();
+        assert_eq!(values, vec![10, 11, 12, 13, 14, 16, 15, 32]);
+    }
+
+    #[test]
+    fn test_register_ids() {
+        let mut cpu = Cpu::new();
+        cpu.registers_mut().set(RegId::BP, 0x1234);
+        cpu.registers_mut()[RegId::R7] = 7;
+        assert_eq!(cpu.registers().get(RegId::BP), cpu.registers().bp());
+        assert_eq!(cpu.registers()[RegId::SP], 256);
+        assert_eq!(cpu.registers()[RegId::R7], 7);
+        assert_eq!(RegId::general(7), Some(RegId::R7));
+        assert_eq!(RegId::general(8), None);
+
+        let names: Vec<&str> = RegId::ALL.iter().map(RegId::name).collect();
+        assert_eq!(names, vec!["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "pc", "sp", "bp"]);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let cpu = Cpu::with_memory_size(1024);
+        assert_eq!((cpu.memory().len(), cpu.registers().sp()), (1024, 1024));
+
+        let mut cpu = Cpu::with_memory_size(0x10000);
+        cpu.load_program(&assemble("push r2\ncall sub\nhalt\nsub: pop r0\npop r1\nhalt").unwrap()).unwrap();
+        cpu.registers[RegId::R2] = 0x1234;
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers.sp), (0x1234, 0));
+        assert_eq!((cpu.memory.data[0xFFFE], cpu.memory.data[0xFFFF]), (0x34, 0x12));
+
+        let mut cpu = Cpu::with_memory_size(0x10000);
+        cpu.load_program(&assemble("pop r0").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Fault(CpuError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_wide_instructions() {
+        let image = assemble("mov r1, 0xAB\nstore r1, [0x1234]\nload r2, [0x1234]\njmp 0x1000").unwrap();
+        assert_eq!(&image[3..7], &[0x81, 0xC8, 0x34, 0x12]);
+        assert_eq!(&image[11..15], &[0xB0, 0x00, 0x00, 0x10]);
+
+        let mut cpu = Cpu::with_memory_size(0x2000);
+        cpu.load_program(&image).unwrap();
+        cpu.memory.data[0x1000] = 0x7F; // halt
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R2], cpu.memory.data[0x1234]), (0xAB, 0xAB));
+
+        // Labels past 0xFF widen the jumps that refer to them.
+        let mut source = String::from("jmp end\n");
+        source += &".byte 0\n".repeat(300);
+        source += "end: halt";
+        let image = assemble(&source).unwrap();
+        assert_eq!(&image[..4], &[0xB0, 0x00, 0x30, 0x01]);
+        assert_eq!(listing(&image).lines().next().unwrap(), "JMP 304                 ; 0000: B0 00 30 01");
+
+        let error = assemble("add r0, 0x10000").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::ImmediateOutOfRange(0x10000));
+        let mut bytes = [0x90, 0x4A].into_iter(); // add r1, r2 with the wide flag
+        assert_eq!(Instruction::decode(0, || bytes.next()), Err(CpuError::InvalidOpcode { opcode: 0x90, pc: 0 }));
+        let mut bytes = [0xFF, 0x40].into_iter(); // halt with the wide flag
+        assert_eq!(Instruction::decode(0, || bytes.next()), Err(CpuError::InvalidOpcode { opcode: 0xFF, pc: 0 }));
+    }
+
+    #[test]
+    fn test_wide_alu() {
+        assert_eq!(assemble("add r1, r2").unwrap(), [0x10, 0x4A]);
+        assert_eq!(assemble("add r1, 0x12").unwrap(), [0x10, 0x08, 0x12]);
+        assert_eq!(assemble("add r1, 0x1234").unwrap(), [0x90, 0x08, 0x34, 0x12]);
+        assert_eq!(assemble("sub r1, -300").unwrap(), [0x91, 0x08, 0xD4, 0xFE]);
+        assert_eq!(assemble("mov r0, -1").unwrap(), [0x82, 0x00, 0xFF, 0xFF]);
+        assert_eq!(assemble("add r1, 255").unwrap(), [0x10, 0x08, 0xFF]);
+
+        // Small negative immediates keep their sign at run time.
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r0, -1\nmov r1, 5\nadd r1, -2\nmov r2, 1\nadd r2, -128\ncmp r0, -1\nhalt").unwrap())
+            .unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R0], cpu.registers[RegId::R1], cpu.registers[RegId::R2]), (0xFFFF, 3, 0xFF81));
+        assert!(cpu.flags().zero() && !cpu.flags().carry() && !cpu.flags().negative());
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r1, 5\nadd r1, -2\ncmp r1, -1\nhalt").unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers[RegId::R1], 3);
+        // 3 - 0xFFFF borrows; as signed numbers 3 > -1, so N == V.
+        assert!(cpu.flags().carry() && !cpu.flags().zero() && cpu.flags().negative() == cpu.flags().overflow());
+
+        let mut cpu = Cpu::new();
+        let source = "mov r1, 0x1234\nmov r2, 0x0101\nadd r1, r2\nmov r3, r1\nand r3, 0xFF00\nxor r3, 0x0F00\n\
+                      mov r4, 300\nmul r4, 200\nmov r5, r4\ndiv r5, 1000\nmov r6, 5\nadd r6, -300\npush 0xBEEF\npop r7\nhalt";
+        cpu.load_program(&assemble(source).unwrap()).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers[RegId::R1], 0x1335);
+        assert_eq!(cpu.registers[RegId::R3], 0x1C00);
+        assert_eq!(cpu.registers[RegId::R4], 60000);
+        assert_eq!(cpu.registers[RegId::R5], 60);
+        assert_eq!(cpu.registers[RegId::R6], 0xFED9);
+        assert_eq!(cpu.registers[RegId::R7], 0xBEEF);
+
+        let listing = listing(&assemble("or r2, 0x4000\nshl r2, r3").unwrap());
+        assert_eq!(listing.lines().collect::<Vec<_>>(), [
+            "OR r2, 16384            ; 0000: A1 10 00 40",
+            "SHL r2, r3              ; 0004: 24 53",
+        ]);
+    }
+
+    // Reference model for the flag specification in the `alu` module, worked out in
+    // wider integers: (result, carry, overflow), or None for a fault.
+    fn alu_reference(opcode: Opcode, a: u16, b: u16, carry: bool) -> Option<(u16, bool, bool)> {
+        let signed_a = a as i16 as i32;
+        let fits_signed = |value: i32| (-0x8000..0x8000).contains(&value);
+        let shift = b.min(40) as u32;
+
+        Some(match opcode {
+            Opcode::ADD | Opcode::INC => {
+                let b = if opcode == Opcode::INC { 1 } else { b };
+                let sum = a as u32 + b as u32;
+                (sum as u16, sum > 0xFFFF, !fits_signed(signed_a + b as i16 as i32))
+            }
+            Opcode::SUB | Opcode::DEC | Opcode::CMP => {
+                let b = if opcode == Opcode::DEC { 1 } else { b };
+                let difference = a as i32 - b as i32;
+                (difference as u16, difference < 0, !fits_signed(signed_a - b as i16 as i32))
+            }
+            Opcode::ADC => {
+                let sum = a as u32 + b as u32 + carry as u32;
+                (sum as u16, sum > 0xFFFF, !fits_signed(signed_a + b as i16 as i32 + carry as i32))
+            }
+            Opcode::SBC => {
+                let difference = a as i32 - b as i32 - carry as i32;
+                (difference as u16, difference < 0, !fits_signed(signed_a - b as i16 as i32 - carry as i32))
+            }
+            Opcode::MUL => {
+                let product = a as u32 * b as u32;
+                (product as u16, product > 0xFFFF, product > 0xFFFF)
+            }
+            Opcode::IMUL => {
+                let product = signed_a * b as i16 as i32;
+                (product as u16, !fits_signed(product), !fits_signed(product))
+            }
+            Opcode::DIV | Opcode::IDIV | Opcode::MOD | Opcode::IMOD if b == 0 => return None,
+            Opcode::DIV => (a / b, false, false),
+            Opcode::IDIV => {
+                let quotient = signed_a / b as i16 as i32;
+                (quotient as u16, false, !fits_signed(quotient))
+            }
+            Opcode::MOD => (a % b, false, false),
+            Opcode::IMOD => ((signed_a % b as i16 as i32) as u16, false, false),
+            Opcode::AND | Opcode::TEST => (a & b, false, false),
+            Opcode::OR => (a | b, false, false),
+            Opcode::XOR => (a ^ b, false, false),
+            Opcode::NOT => (!a, false, false),
+            Opcode::SHL => {
+                let shifted = (a as u64) << shift;
+                (shifted as u16, (shifted >> 16) & 1 == 1, false)
+            }
+            Opcode::SHR => (((a as u64) >> shift) as u16, (((a as u64) << 1) >> shift) & 1 == 1, false),
+            Opcode::SAR => {
+                let signed_a = signed_a as i64;
+                ((signed_a >> shift) as u16, ((signed_a << 1) >> shift) & 1 == 1, false)
+            }
+            Opcode::RCL | Opcode::RCR => {
+                // Rotate one bit at a time.
+                let (mut value, mut carry) = (a, carry);
+                for _ in 0..b {
+                    (value, carry) = match opcode {
+                        Opcode::RCL => (value << 1 | carry as u16, value & 0x8000 != 0),
+                        _ => (value >> 1 | (carry as u16) << 15, value & 1 != 0),
+                    };
+                }
+                (value, carry, false)
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn test_alu_flags() {
+        let opcodes = [
+            Opcode::ADD, Opcode::SUB, Opcode::MUL, Opcode::DIV, Opcode::INC, Opcode::DEC,
+            Opcode::AND, Opcode::OR, Opcode::XOR, Opcode::NOT, Opcode::SHL, Opcode::SHR,
+            Opcode::CMP, Opcode::TEST, Opcode::ADC, Opcode::SBC, Opcode::RCL, Opcode::RCR,
+            Opcode::IMUL, Opcode::IDIV, Opcode::MOD, Opcode::IMOD, Opcode::SAR,
+        ];
+        let values = [0, 1, 2, 7, 15, 16, 17, 0x00FF, 0x0100, 0x1234, 0x7FFF, 0x8000, 0x8001, 0xAAAA, 0xFFFE, 0xFFFF];
+
+        for (opcode, carry) in opcodes.into_iter().flat_map(|opcode| [(opcode, false), (opcode, true)]) {
+            for a in values {
+                for b in values {
+                    let mut cpu = Cpu::new();
+                    cpu.memory.data[..2].copy_from_slice(&[opcode as u8, 0b0100_0001]); // op r0, r1
+                    cpu.registers[RegId::R0] = a;
+                    cpu.registers[RegId::R1] = b;
+                    cpu.flags.interrupt = true;
+                    cpu.flags.carry = carry;
+
+                    let case = format!("{} 0x{:04X}, 0x{:04X} with C={}", opcode.mnemonic(), a, b, carry as u8);
+                    match alu_reference(opcode, a, b, carry) {
+                        Some((value, carry, overflow)) => {
+                            cpu.step().unwrap();
+                            let flags = cpu.flags();
+                            let written = if matches!(opcode, Opcode::CMP | Opcode::TEST) { a } else { value };
+                            assert_eq!(cpu.registers[RegId::R0], written, "{}", case);
+                            assert_eq!((flags.zero(), flags.negative()), (value == 0, value >= 0x8000), "{}", case);
+                            assert_eq!((flags.carry(), flags.overflow()), (carry, overflow), "{}", case);
+                            assert!(flags.interrupt(), "{}", case);
+                        }
+                        None => assert_eq!(cpu.step(), Err(CpuError::DivideByZero), "{}", case),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_precision() {
+        // Two 32-bit numbers in r1:r0 and r3:r2, high words first.
+        let run = |source: &str, a: u32, b: u32| {
+            let mut cpu = Cpu::new();
+            cpu.load_program(&assemble(source).unwrap()).unwrap();
+            cpu.registers[RegId::R0] = a as u16;
+            cpu.registers[RegId::R1] = (a >> 16) as u16;
+            cpu.registers[RegId::R2] = b as u16;
+            cpu.registers[RegId::R3] = (b >> 16) as u16;
+            assert_eq!(cpu.run(), HaltReason::Halted);
+            cpu
+        };
+        let word = |cpu: &Cpu, high: RegId, low: RegId| (cpu.registers[high] as u32) << 16 | cpu.registers[low] as u32;
+
+        // 32 x 32 -> 64 bit multiply from four partial products, result in r7:r6:r5:r4.
+        let multiply = "mov r4, r0\nmov r5, r2\nmulx r4, r5\nmov r6, r1\nmov r7, r3\nmulx r6, r7\n\
+                        mulx r0, r3\nadd r5, r0\nadc r6, r3\nadc r7, 0\n\
+                        mulx r1, r2\nadd r5, r1\nadc r6, r2\nadc r7, 0\nhalt";
+
+        let values = [0, 1, 0xFFFF, 0x1_0000, 0x1234_FFFF, 0x7FFF_FFFF, 0x8000_0000, 0xDEAD_BEEF, 0xFFFF_FFFF];
+        for a in values {
+            for b in values {
+                let case = format!("0x{:08X}, 0x{:08X}", a, b);
+
+                let cpu = run("add r0, r2\nadc r1, r3\nhalt", a, b);
+                assert_eq!(word(&cpu, RegId::R1, RegId::R0), a.wrapping_add(b), "add {}", case);
+                assert_eq!(cpu.flags().carry(), a.checked_add(b).is_none(), "add {}", case);
+
+                let cpu = run("sub r0, r2\nsbc r1, r3\nhalt", a, b);
+                assert_eq!(word(&cpu, RegId::R1, RegId::R0), a.wrapping_sub(b), "sub {}", case);
+                assert_eq!(cpu.flags().carry(), b > a, "sub {}", case);
+                assert_eq!(cpu.flags().overflow(), (a as i32).checked_sub(b as i32).is_none(), "sub {}", case);
+
+                let cpu = run(multiply, a, b);
+                let product = (word(&cpu, RegId::R7, RegId::R6) as u64) << 32 | word(&cpu, RegId::R5, RegId::R4) as u64;
+                assert_eq!(product, a as u64 * b as u64, "multiply {}", case);
+            }
+
+            let cpu = run("shl r0, 1\nrcl r1, 1\nhalt", a, 0);
+            assert_eq!((word(&cpu, RegId::R1, RegId::R0), cpu.flags().carry()), (a << 1, a >> 31 == 1));
+            let cpu = run("shr r1, 1\nrcr r0, 1\nhalt", a, 0);
+            assert_eq!((word(&cpu, RegId::R1, RegId::R0), cpu.flags().carry()), (a >> 1, a & 1 == 1));
+        }
+
+        let cpu = run("mulx r0, r2\nhalt", 0x8000, 0xFFFF);
+        assert_eq!((cpu.registers[RegId::R0], cpu.registers[RegId::R2]), (0x8000, 0x7FFF));
+        assert_eq!((cpu.flags().carry(), cpu.flags().overflow(), cpu.flags().negative()), (true, true, false));
+        let cpu = run("mulx r0, r2\nhalt", 0, 0x1234);
+        assert_eq!((cpu.flags().zero(), cpu.flags().carry()), (true, false));
+
+        // MULX only takes two different registers.
+        let error = assemble("nop\nmulx r0, 3").unwrap_err();
+        assert_eq!((error.line, error.column, error.kind), (2, 10, AsmErrorKind::InvalidOperand("3".to_string())));
+        let error = assemble("mulx r1, r1").unwrap_err();
+        assert_eq!((error.line, error.column, error.kind), (1, 10, AsmErrorKind::RepeatedRegister("r1".to_string())));
+        let mut cpu = Cpu::new();
+        cpu.load_program(&[0x19, 0x08, 0x03]).unwrap(); // mulx r1 with an immediate source
+        assert_eq!(cpu.step(), Err(CpuError::InvalidAddressingMode { opcode: Opcode::MULX, mode: AddressingMode::Immediate }));
+        let mut bytes = [0x99, 0x08, 0x03, 0x00].into_iter();
+        assert_eq!(Instruction::decode(0, || bytes.next()), Err(CpuError::InvalidOpcode { opcode: 0x99, pc: 0 }));
+    }
+
+    #[test]
+    fn test_signed_arithmetic() {
+        let run = |source: &str| {
+            let mut cpu = Cpu::new();
+            cpu.load_program(&assemble(source).unwrap()).unwrap();
+            assert_eq!(cpu.run(), HaltReason::Halted);
+            cpu
+        };
+
+        let cpu = run("mov r1, -7\nmov r2, r1\nmov r3, r1\nmov r4, r1\nidiv r1, 2\nimod r2, 2\nsar r3, 1\nimul r4, -3\n\
+                       mov r0, 5\nimul r0, -2\nmov r5, 7\nidiv r5, -2\nhalt");
+        let signed = |id| cpu.registers[id] as i16;
+        assert_eq!((signed(RegId::R1), signed(RegId::R2), signed(RegId::R3), signed(RegId::R4)), (-3, -1, -4, 21));
+        assert_eq!((signed(RegId::R0), signed(RegId::R5)), (-10, -3));
+        let cpu = run("mov r1, -7\nmod r1, 2\nmov r2, -7\nshr r2, 1\nhalt");
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R2]), (1, 0x7FFC));
+
+        // -32768 / -1 does not fit and wraps back to -32768.
+        let cpu = run("mov r1, -32768\nidiv r1, -1\nhalt");
+        let flags = cpu.flags();
+        assert_eq!(cpu.registers[RegId::R1], 0x8000);
+        assert_eq!((flags.negative(), flags.overflow(), flags.carry(), flags.zero()), (true, true, false, false));
+        let cpu = run("mov r1, -32768\nimod r1, -1\nhalt");
+        assert_eq!((cpu.registers[RegId::R1], cpu.flags().zero(), cpu.flags().overflow()), (0, true, false));
+        let cpu = run("mov r1, -32768\nimul r1, -1\nhalt");
+        assert_eq!((cpu.registers[RegId::R1], cpu.flags().negative(), cpu.flags().overflow()), (0x8000, true, true));
+
+        let cpu = run("mov r1, 0x8000\nsar r1, 20\nmov r2, 0x4000\nsar r2, 20\nhalt");
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R2]), (0xFFFF, 0));
+
+        // Signed comparisons against negative literals.
+        let cpu = run("mov r1, -2\ncmp r1, -1\njl less\nhalt\n\
+                       less: mov r2, 1\ncmp r1, -3\njg greater\nhalt\n\
+                       greater: mov r3, 1\nhalt");
+        assert_eq!((cpu.registers[RegId::R2], cpu.registers[RegId::R3]), (1, 1));
+
+        for source in ["idiv r1, 0", "imod r1, 0", "mod r1, r2"] {
+            let mut cpu = Cpu::new();
+            cpu.load_program(&assemble(source).unwrap()).unwrap();
+            assert_eq!(cpu.step(), Err(CpuError::DivideByZero), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_conditional_branches() {
+        // Each jump's condition as a comparison of `cmp a, b`, for those that have one.
+        let condition = |mnemonic: &str, a: u16, b: u16| match mnemonic {
+            "jz" => a == b,
+            "jnz" => a != b,
+            "jb" => a < b,
+            "jae" => a >= b,
+            "ja" => a > b,
+            "jl" => (a as i16) < (b as i16),
+            "jge" => (a as i16) >= (b as i16),
+            "jg" => (a as i16) > (b as i16),
+            "jle" => (a as i16) <= (b as i16),
+            _ => unreachable!(),
+        };
+        let values = [0, 1, 2, 0x7FFE, 0x7FFF, 0x8000, 0x8001, 0xFFFE, 0xFFFF];
+
+        for mnemonic in ["jz", "jnz", "jb", "jae", "ja", "jl", "jge", "jg", "jle"] {
+            for a in values {
+                for b in values {
+                    let source = format!("mov r1, {}\nmov r2, {}\ncmp r1, r2\n{} taken\nhalt\ntaken: mov r3, 1\nhalt", a, b, mnemonic);
+                    let mut cpu = Cpu::new();
+                    cpu.load_program(&assemble(&source).unwrap()).unwrap();
+                    assert_eq!(cpu.run(), HaltReason::Halted);
+                    let case = format!("cmp 0x{:04X}, 0x{:04X}; {}", a, b, mnemonic);
+                    assert_eq!(cpu.registers[RegId::R3] == 1, condition(mnemonic, a, b), "{}", case);
+                    assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R2]), (a, b), "{}", case);
+                }
+            }
+        }
+
+        // The single-flag jumps, after results that set and clear each flag.
+        let cases = [
+            ("sub r1, 1", "jn", true), ("sub r1, 1", "jnn", false),
+            ("add r1, 1", "jn", false), ("add r1, 1", "jnn", true),
+            ("sub r1, 0x8000", "jo", true), ("sub r1, 0x8000", "jno", false),
+            ("add r1, 1", "jo", false), ("add r1, 1", "jno", true),
+            ("sub r1, 1", "jc", true), ("sub r1, 1", "jnc", false),
+            ("test r1, 0", "jc", false), ("test r1, 0", "jnc", true),
+            ("test r1, 0", "jz", true), ("not r1\ntest r1, 1", "jz", false),
+        ];
+        for (setup, jump, taken) in cases {
+            let source = format!("mov r1, 0\n{}\n{} taken\nhalt\ntaken: mov r3, 1\nhalt", setup, jump);
+            let mut cpu = Cpu::new();
+            cpu.load_program(&assemble(&source).unwrap()).unwrap();
+            assert_eq!(cpu.run(), HaltReason::Halted);
+            assert_eq!(cpu.registers[RegId::R3] == 1, taken, "{}; {}", setup, jump);
+        }
+
+        assert_eq!(assemble("jb 0\njae 0").unwrap(), assemble("jc 0\njnc 0").unwrap());
+        assert_eq!(listing(&assemble("cmp r1, 5\ntest r2, r3\njle 0").unwrap()).lines().collect::<Vec<_>>(), [
+            "CMP r1, 5               ; 0000: 16 08 05",
+            "TEST r2, r3             ; 0003: 26 53",
+            "JLE 0                   ; 0005: 3E 00 00",
+        ]);
+    }
+
+    // Latches the last byte written and counts reads.
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+        reads: u8,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, _offset: u16) -> u8 {
+            self.reads += 1;
+            self.value + self.reads
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.value = value;
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let program = assemble("mov r1, 0x41\nstore r1, [0x80]\nload r2, [0x80]\nstore r2, [0x40]\nhalt").unwrap();
+        let map = MemoryMap::new(0x100)
+            .rom(0x00, program, RomWrites::Fault)
+            .ram(0x40, 0x40)
+            .device(0x80, 1, Box::new(Latch::default()))
+            .ram(0xC0, 0x40);
+
+        let mut cpu = Cpu::with_bus(map);
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers[RegId::R2], 0x42);
+        assert_eq!(cpu.memory().peek(0x40), Some(0x42));
+        assert_eq!(cpu.memory().peek(0x80), None);
+        assert_eq!(cpu.memory().peek(0x90), None);
+
+        let mut map = MemoryMap::new(0x100).rom(0x00, vec![0x7F, 0x40], RomWrites::Fault);
+        assert_eq!(map.write(0x01, 0), Err(CpuError::ReadOnly { address: 0x01 }));
+        assert_eq!(map.read(0x02), Err(CpuError::MemoryFault { address: 0x02 }));
+
+        let mut map = MemoryMap::new(0x100).rom(0x00, vec![0x7F, 0x40], RomWrites::Ignore);
+        assert_eq!(map.write(0x01, 0), Ok(()));
+        assert_eq!(map.read(0x01), Ok(0x40));
+    }
+
+    #[test]
+    fn test_uart_console() {
+        let program = assemble(
+            "mov r0, 104\nstore r0, [0xF0]\nmov r0, 105\nstore r0, [0xF0]\n\
+             echo: load r1, [0xF1]\nand r1, 1\njz done\nload r0, [0xF0]\nstore r0, [0xF0]\njmp echo\n\
+             done: halt",
+        )
+        .unwrap();
+        let uart = Uart::new();
+        uart.push_input(b", you");
+        let map = MemoryMap::new(0x100)
+            .ram(0x00, 0xF0)
+            .device(0xF0, 2, Box::new(uart.clone()))
+            .ram(0xF2, 0x0E);
+
+        let mut cpu = Cpu::with_bus(map);
+        cpu.load_program(&program).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(uart.take_output(), b"hi, you");
+        assert_eq!(uart.output(), b"");
+        assert_eq!(cpu.memory().peek(0xF1), Some(0b10));
+
+        // Input from a source shows up as it is sent, behind bytes already pushed.
+        let (sender, receiver) = mpsc::channel();
+        let mut uart = Uart::new().with_source(receiver);
+        uart.push_input(b"a");
+        assert_eq!((uart.read(0), uart.read(1)), (b'a', 0b10));
+        thread::spawn(move || sender.send(b"bc".to_vec()).unwrap()).join().unwrap();
+        assert_eq!(uart.read(1), 0b11);
+        assert_eq!((uart.read(0), uart.read(0), uart.read(1)), (b'b', b'c', 0b10));
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let program = assemble("ei\nnop\nnop\nhalt\nmov r1, r3\niret\nmov r3, 3\niret").unwrap();
+        let setup = |masked: u8| {
+            let mut cpu = Cpu::new();
+            cpu.load_program(&program).unwrap();
+            cpu.memory.data[0xE2] = 8; // line 1
+            cpu.memory.data[0xE6] = 12; // line 3
+            cpu.interrupts().set_vector_base(0xE0);
+            cpu.interrupts().set_masked(masked);
+            cpu.interrupts().raise(3);
+            cpu.interrupts().raise(1);
+            cpu
+        };
+
+        // Line 1 wins and its handler runs before line 3 sets r3.
+        let mut cpu = setup(0);
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R3]), (0, 3));
+        assert_eq!((cpu.interrupts().pending(), cpu.registers.sp), (0, 256));
+        assert!(cpu.flags().interrupt());
+
+        let mut cpu = setup(0b1000);
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!((cpu.registers[RegId::R1], cpu.registers[RegId::R3]), (0, 0));
+        assert_eq!(cpu.interrupts().pending(), 0b1000);
+
+        // Nothing is taken until EI executes.
+        let mut cpu = setup(0);
+        cpu.step().unwrap();
+        assert_eq!((cpu.registers.pc, cpu.registers.sp), (2, 256));
+        cpu.step().unwrap();
+        assert_eq!((cpu.registers.pc, cpu.registers.sp), (10, 252));
+        assert!(!cpu.flags().interrupt());
+    }
+
+    fn timer_cpu(source: &str) -> Cpu<MemoryMap> {
+        let interrupts = InterruptController::new();
+        interrupts.set_vector_base(0xE0);
+        let map = MemoryMap::new(0x100)
+            .ram(0x00, 0xF0)
+            .device(0xF0, 5, Box::new(Timer::new(interrupts.clone(), 2)))
+            .ram(0xF5, 0x0B);
+
+        let mut cpu = Cpu::with_bus(map).with_interrupts(interrupts);
+        cpu.load_program(&assemble(source).unwrap()).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_timer_periodic() {
+        let mut cpu = timer_cpu(
+            "mov r0, isr\nstore r0, [0xE4]\nmov r0, 5\nstore r0, [0xF0]\nmov r0, 3\nstore r0, [0xF4]\nei\n\
+             wait: inc r2\njmp wait\n\
+             isr: inc r1\nmov r3, r1\nsub r3, 3\njz done\niret\ndone: halt",
+        );
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers[RegId::R1], 3);
+        assert_eq!(cpu.memory().peek(0xF4), Some(0b11));
+    }
+
+    #[test]
+    fn test_timer_one_shot() {
+        let mut cpu = timer_cpu(
+            "mov r0, isr\nstore r0, [0xE4]\nmov r0, 4\nstore r0, [0xF0]\nmov r0, 1\nstore r0, [0xF4]\nei\n\
+             mov r5, 30\nspin: dec r5\njnz spin\nhalt\n\
+             isr: inc r1\niret",
+        );
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers[RegId::R1], 1);
+        assert_eq!(cpu.memory().peek(0xF4).map(|control| control & TIMER_ENABLE), Some(0));
+        assert_eq!(cpu.interrupts().pending(), 0);
+    }
+
+    #[test]
+    fn test_step_outcome() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r1, 0x12\nstore r1, [0x40]\ncall 11\nhalt\nsub r1, 0x12\nret").unwrap()).unwrap();
+        cpu.memory.data[0x40] = 5;
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!((outcome.pc_before, outcome.pc_after, outcome.interrupt), (0, 3, None));
+        assert_eq!(outcome.registers, vec![
+            RegisterChange { register: RegId::R1, old: 0, new: 0x12 },
+            RegisterChange { register: RegId::PC, old: 0, new: 3 },
+        ]);
+        assert!(outcome.writes.is_empty());
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(outcome.writes, vec![MemoryWrite { address: 0x40, old: Some(5), new: 0x12 }]);
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(outcome.instruction.opcode(), Opcode::CALL);
+        assert_eq!(outcome.writes, vec![
+            MemoryWrite { address: 255, old: Some(0), new: 0 },
+            MemoryWrite { address: 254, old: Some(0), new: 9 },
+        ]);
+        assert_eq!(outcome.registers[1], RegisterChange { register: RegId::SP, old: 256, new: 254 });
+
+        let outcome = cpu.step().unwrap();
+        assert!(outcome.flags_after.zero() && !outcome.flags_before.zero());
+        cpu.step().unwrap();
+        assert!(cpu.step().unwrap().halted);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let program = assemble("mov r0, 2\nloop: dec r0\njnz loop\nhalt").unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&program).unwrap();
+        assert_eq!(cpu.step().unwrap().cycles, 2);
+        assert_eq!(cpu.step().unwrap().cycles, 1);
+        assert_eq!(cpu.step().unwrap().cycles, 3); // taken
+        assert_eq!(cpu.run_for_cycles(2), HaltReason::BudgetExhausted { pc: 8 });
+        assert_eq!(cpu.cycles(), 9);
+        assert_eq!(cpu.run_for_cycles(100), HaltReason::Halted);
+        assert_eq!(cpu.cycles(), 10);
+
+        let mut cpu = Cpu::new().with_costs(CycleCosts::new().with_opcode(Opcode::DEC, 5).with_taken_branch(0));
+        cpu.load_program(&program).unwrap();
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.cycles(), 17);
+    }
+
+    #[test]
+    fn test_run_with_limit() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("loop: inc r0\njmp loop").unwrap()).unwrap();
+        assert_eq!(cpu.run_with_limit(5), HaltReason::BudgetExhausted { pc: 2 });
+        assert_eq!(cpu.registers[RegId::R0], 3);
+        assert_eq!(cpu.run_with_limit(0), HaltReason::BudgetExhausted { pc: 2 });
+        assert_eq!(cpu.run_for_cycles(7), HaltReason::BudgetExhausted { pc: 0 });
+        assert_eq!(cpu.registers[RegId::R0], 5);
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("inc r0\nhalt").unwrap()).unwrap();
+        assert_eq!(cpu.run_with_limit(2), HaltReason::Halted);
+        assert_eq!(cpu.run_with_limit(2), HaltReason::Halted);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let source = "mov r0, isr\nstore r0, [0xE4]\nmov r0, 5\nstore r0, [0xF0]\nmov r0, 3\nstore r0, [0xF4]\nei\n\
+                      wait: inc r2\njmp wait\n\
+                      isr: inc r1\nstore r1, [0x80]\nmov r3, r1\nsub r3, 3\njz done\niret\ndone: halt";
+        let mut cpu = timer_cpu(source);
+        cpu.run_with_limit(12);
+        let snapshot = cpu.snapshot();
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot.clone()));
+        assert_eq!(cpu.run(), HaltReason::Halted);
+
+        let mut restored = timer_cpu("halt");
+        restored.restore(&Snapshot::from_bytes(&snapshot.to_bytes()).unwrap()).unwrap();
+        assert_eq!(restored.current_instruction(), snapshot.current_instruction.as_ref());
+        assert_eq!(restored.run(), HaltReason::Halted);
+        assert_eq!(restored.registers(), cpu.registers());
+        assert_eq!((restored.cycles(), restored.memory().peek(0x80)), (cpu.cycles(), Some(3)));
+
+        let bytes = Cpu::new().snapshot().to_bytes();
+        assert_eq!(Snapshot::from_bytes(b"RCP"), Err(SnapshotError::NotASnapshot));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(Snapshot::from_bytes(&newer), Err(SnapshotError::UnsupportedVersion(2)));
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated));
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(Cpu::with_memory_size(512).restore(&snapshot), Err(SnapshotError::Mismatch("memory size")));
+        assert_eq!(timer_cpu("halt").restore(&snapshot), Err(SnapshotError::Mismatch("memory map")));
+    }
+
+    // A writer whose contents stay readable after it is boxed into a tracer.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let program = assemble("mov r1, 0x12\nload r2, [0x40]\npush r1\nhalt").unwrap();
+        let traced = |format| {
+            let buffer = SharedBuffer::default();
+            let mut cpu = Cpu::new();
+            cpu.load_program(&program).unwrap();
+            cpu.memory.data[0x40] = 5;
+            cpu.set_tracer(Box::new(WriteTracer::new(buffer.clone(), format)));
+            assert_eq!(cpu.run(), HaltReason::Halted);
+            assert!(cpu.take_tracer().unwrap().flush().is_ok());
+            let bytes = buffer.0.borrow().clone();
+            bytes
+        };
+
+        let text = String::from_utf8(traced(TraceFormat::Text)).unwrap();
+        assert_eq!(text.lines().collect::<Vec<_>>(), vec![
+            "0000: 02 08 12    MOV r1, 18          r1=0012",
+            "0003: 00 D0 40    LOAD r2, [0x40]     r2=0005 [0040]->05",
+            "0006: 40 41       PUSH r1             sp=00FE [00FF]<-00 [00FE]<-12",
+            "0008: 7F 40       HALT                halted",
+        ]);
+
+        let json = String::from_utf8(traced(TraceFormat::Json)).unwrap();
+        assert_eq!(
+            json.lines().nth(1).unwrap(),
+            "{\"address\":3,\"bytes\":[0,208,64],\"instruction\":\"LOAD r2, [0x40]\",\"interrupt\":null,\
+             \"registers\":{\"r2\":5,\"pc\":6},\"flags\":{\"before\":0,\"after\":0},\
+             \"reads\":[{\"address\":64,\"value\":5}],\"writes\":[],\"halted\":false}"
+        );
+
+        let binary = traced(TraceFormat::Binary);
+        assert_eq!(binary[..19], [0, 0, 3, 2, 8, 18, 0xFF, 0, 0, 2, 1, 18, 0, 8, 3, 0, 0, 0, 0]);
+        assert_eq!(binary.len(), 80);
+    }
+
+    fn debugger(source: &str) -> Debugger {
+        let program = assemble_program(source).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&program.image).unwrap();
+        Debugger::new(cpu).with_labels(program.labels)
+    }
+
+    #[test]
+    fn test_debugger_stops() {
+        let source = "mov r0, 3\ncall double\ncall double\nstore r0, [0x40]\nhalt\ndouble: add r0, r0\nret";
+        let mut debugger = debugger(source);
+
+        assert_eq!(debugger.step_over(), Stop::Done);
+        assert_eq!(debugger.step_over(), Stop::Done);
+        assert_eq!((debugger.cpu().registers().pc(), debugger.cpu().registers()[RegId::R0]), (6, 6));
+        debugger.add_breakpoint(14);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(14));
+        assert!(debugger.remove_breakpoint(14));
+
+        let mut debugger = self::debugger(source);
+        debugger.add_breakpoint(14);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(14));
+        assert_eq!(debugger.finish(), Stop::Done);
+        assert_eq!(debugger.cpu().registers().pc(), 6);
+
+        debugger.add_watchpoint(0x40);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(14));
+        assert_eq!(debugger.resume(), Stop::Watchpoint(MemoryWrite { address: 0x40, old: Some(0), new: 12 }));
+        assert_eq!(debugger.resume(), Stop::Halted);
+        assert_eq!(debugger.step(), Stop::Fault(CpuError::Halted));
+    }
+
+    #[test]
+    fn test_debugger_commands() {
+        let mut debugger = debugger("mov r0, 3\ncall double\nhalt\ndouble: add r0, r0\nret");
+
+        assert_eq!(debugger.execute("break double"), Ok("breakpoint at 0x0008".to_string()));
+        assert_eq!(debugger.execute("c"), Ok("breakpoint at 0x0008\n=> 0x0008: ADD r0, r0".to_string()));
+        assert_eq!(debugger.execute("step"), Ok("=> 0x000A: RET".to_string()));
+        assert_eq!(debugger.execute("regs").unwrap().lines().next(), Some("r0 0x0006"));
+        assert_eq!(debugger.execute("flags"), Ok("Z=0 N=0 C=0 V=0 I=0".to_string()));
+        assert_eq!(debugger.execute("x/3 0xFC"), Ok("0x00FC: 00 00 06".to_string()));
+        assert_eq!(debugger.execute("disas 6").unwrap().lines().next(), Some("   0x0006: HALT"));
+        assert_eq!(debugger.execute(""), Ok(String::new()));
+
+        assert_eq!(debugger.execute("bogus"), Err(CommandError::UnknownCommand("bogus".to_string())));
+        assert_eq!(debugger.execute("break"), Err(CommandError::MissingArgument("address")));
+        assert_eq!(debugger.execute("watch nowhere"), Err(CommandError::InvalidAddress("nowhere".to_string())));
+        assert_eq!(debugger.execute("x/many 0"), Err(CommandError::InvalidCount("many".to_string())));
+        assert_eq!(debugger.execute("x/99999999999 0"), Err(CommandError::InvalidCount("99999999999".to_string())));
+        assert_eq!(debugger.execute("x/256 0").unwrap().lines().count(), 32);
+        assert_eq!(debugger.execute("x/257 0"), Err(CommandError::InvalidCount("257".to_string())));
+    }
+
+    #[test]
+    fn test_debugger_disas_around_pc() {
+        let mut debugger = debugger("mov r0, 3\ncall double\nhalt\ndouble: add r0, r0\nret");
+        assert_eq!(debugger.execute("disas").unwrap().lines().next(), Some("=> 0x0000: MOV r0, 3"));
+
+        debugger.execute("break double").unwrap();
+        debugger.execute("c").unwrap();
+        let listing = debugger.execute("disas").unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[..3], ["   0x0003: CALL 8", "   0x0006: HALT", "=> 0x0008: ADD r0, r0"]);
+
+        // Decoding resyncs at labels, so data ahead of the code does not misalign the listing.
+        let mut debugger = self::debugger("jmp start\n.byte 0x01\nstart: mov r1, 1\nhalt");
+        debugger.step();
+        assert_eq!(debugger.execute("disas").unwrap().lines().next(), Some("   0x0000: JMP 4"));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r0, 3\ncall double\nstore r0, [0x40]\nhalt\ndouble: add r0, r0\nret").unwrap())
+            .unwrap();
+        cpu.set_history_depth(16);
+
+        let mut snapshots = vec![cpu.snapshot()];
+        while cpu.is_running() {
+            cpu.step().unwrap();
+            snapshots.push(cpu.snapshot());
+        }
+        assert_eq!(cpu.memory().data[0x40], 6);
+
+        snapshots.pop();
+        while let Some(snapshot) = snapshots.pop() {
+            assert!(cpu.step_back().unwrap());
+            assert_eq!(cpu.snapshot(), snapshot);
+        }
+        assert!(!cpu.step_back().unwrap());
+        assert_eq!(cpu.run(), HaltReason::Halted);
+
+        cpu.set_history_depth(2);
+        assert_eq!(cpu.history_len(), 2);
+        assert!(cpu.step_back().unwrap() && cpu.step_back().unwrap());
+        assert!(!cpu.step_back().unwrap());
+        assert_eq!(cpu.registers().pc(), 6);
+
+        // Undoing a store to a device leaves the device alone, but RAM is rewound.
+        let uart = Uart::new();
+        let map = MemoryMap::new(0x100).ram(0x00, 0xF0).device(0xF0, 2, Box::new(uart.clone())).ram(0xF2, 0x0E);
+        let mut cpu = Cpu::with_bus(map);
+        cpu.load_program(&assemble("mov r0, 0x41\nstore r0, [0xF0]\nstore r0, [0x80]\nhalt").unwrap()).unwrap();
+        cpu.set_history_depth(16);
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        while cpu.step_back().unwrap() {}
+        assert_eq!(uart.output(), b"A");
+        assert_eq!(cpu.memory().peek(0x80), Some(0));
+        assert_eq!(cpu.registers().pc(), 0);
+    }
+
+    #[test]
+    fn test_debugger_reverse() {
+        let mut debugger = debugger("mov r0, 3\ncall double\ncall double\nhalt\ndouble: add r0, r0\nret").with_history(64);
+
+        assert_eq!(debugger.resume(), Stop::Halted);
+        assert_eq!(debugger.cpu().registers()[RegId::R0], 12);
+        debugger.add_breakpoint(11);
+        assert_eq!(debugger.reverse_continue(), Stop::Breakpoint(11));
+        assert_eq!(debugger.cpu().registers()[RegId::R0], 6);
+        assert_eq!(debugger.execute("rs"), Ok("=> 0x0006: CALL 11".to_string()));
+        assert_eq!(debugger.execute("reverse-continue"), Ok("breakpoint at 0x000B\n=> 0x000B: ADD r0, r0".to_string()));
+        assert_eq!(debugger.cpu().registers()[RegId::R0], 3);
+        assert_eq!(debugger.reverse_continue(), Stop::HistoryStart);
+        assert_eq!(debugger.cpu().registers().pc(), 0);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(11));
+    }
+
+    // Plays the client side of a GDB session over local TCP, returning each reply.
+    fn gdb_session(stub: &mut GdbStub, packets: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let packets: Vec<String> = packets.iter().map(|packet| packet.to_string()).collect();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets {
+                let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+                write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], b'+');
+                replies.push(gdb_reply(&mut stream));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        stub.serve(stream).unwrap();
+        client.join().unwrap()
+    }
+
+    // Reads one reply packet from the stub and acknowledges it.
+    fn gdb_reply(stream: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(&assemble("mov r0, 3\ncall double\nhalt\ndouble: add r0, r0\nret").unwrap()).unwrap();
+        let mut stub = GdbStub::new(Debugger::new(cpu).with_history(16));
+
+        let replies = gdb_session(
+            &mut stub,
+            &[
+                "qSupported:multiprocess+", "?", "Z0,8,2", "c", "p8", "g", "mfe,2", "M40,2:abcd", "m40,2",
+                "P0=0500", "z0,8,2", "s", "p0", "qXfer:features:read:target.xml:0,5", "vMustReplyEmpty", "c", "bs",
+                "bc", "D",
+            ],
+        );
+        let registers = format!("0300{}0800fe0000000000", "0000".repeat(7)); // r0-r7, pc, sp, bp, flags
+        let expected = [
+            "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+", "S05", "OK", "S05", "0800", &registers,
+            "0600", "OK", "abcd", "OK", "OK", "S05", "0a00", "m<?xml", "", "W00", "S05", "T05replaylog:begin;", "OK",
+        ];
+        assert_eq!(replies, expected);
+        assert_eq!(stub.debugger().cpu().memory().peek(0x41), Some(0xCD));
+        assert_eq!(stub.debugger().cpu().registers().pc(), 0);
+
+        // Ranges past the 16-bit address space or longer than a reply packet are refused.
+        let mut stub = GdbStub::new(Debugger::new(Cpu::with_memory_size(0x10000)));
+        let replies = gdb_session(
+            &mut stub,
+            &["Mffffffff,2:0000", "m0,ffffffff", "mffff,2", "MFFFF,2:0000", "m10000,1", "m0,801", "mfffe,2", "D"],
+        );
+        assert_eq!(replies, ["E01", "E01", "E01", "E01", "E01", "E01", "0000", "OK"]);
+
+        // A packet longer than the advertised PacketSize is NAKed and the stub resyncs on the next one.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let packet = format!("M0,1:{}", "0".repeat(0x1000));
+            let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(stream, "${}#{:02x}$?#3f", packet, checksum).unwrap();
+            let mut acks = [0; 2];
+            stream.read_exact(&mut acks).unwrap();
+            assert_eq!(&acks, b"-+");
+            let reply = gdb_reply(&mut stream);
+            write!(stream, "$D#44").unwrap();
+            stream.read_exact(&mut [0]).unwrap();
+            assert_eq!(gdb_reply(&mut stream), "OK");
+            reply
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(Debugger::new(Cpu::new())).serve(stream).unwrap();
+        assert_eq!(client.join().unwrap(), "S05");
+    }
+}
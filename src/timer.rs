@@ -0,0 +1,106 @@
+use crate::snapshot::Reader;
+use crate::{Device, InterruptController, SnapshotError};
+
+/// Offset of the little-endian reload word the counter restarts from.
+pub const TIMER_RELOAD: u16 = 0;
+/// Offset of the little-endian counter word. Writes are ignored.
+pub const TIMER_COUNTER: u16 = 2;
+/// Offset of the control register.
+pub const TIMER_CONTROL: u16 = 4;
+/// Control bit that runs the timer. Writing it loads the counter from the reload word.
+pub const TIMER_ENABLE: u8 = 0b01;
+/// Control bit that restarts the count after expiry instead of stopping.
+pub const TIMER_PERIODIC: u8 = 0b10;
+
+/// An interval timer counting down once per CPU cycle.
+///
+/// When the counter reaches zero the timer raises its IRQ line, then either
+/// reloads (periodic mode) or clears [`TIMER_ENABLE`] (one-shot mode).
+/// Occupies five bytes of address space.
+#[derive(Debug)]
+pub struct Timer {
+    reload: u16,
+    counter: u16,
+    control: u8,
+    interrupts: InterruptController,
+    line: u8,
+}
+
+impl Timer {
+    /// A stopped timer raising `line` on `interrupts` when it expires.
+    pub fn new(interrupts: InterruptController, line: u8) -> Self {
+        Timer {
+            reload: 0,
+            counter: 0,
+            control: 0,
+            interrupts,
+            line,
+        }
+    }
+
+    fn register(&self, offset: u16) -> u8 {
+        match offset {
+            0 => self.reload as u8,
+            1 => (self.reload >> 8) as u8,
+            2 => self.counter as u8,
+            3 => (self.counter >> 8) as u8,
+            TIMER_CONTROL => self.control,
+            _ => 0
+        }
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.register(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            0 => self.reload = (self.reload & 0xFF00) | value as u16,
+            1 => self.reload = (self.reload & 0x00FF) | (value as u16) << 8,
+            TIMER_CONTROL => {
+                self.control = value & (TIMER_ENABLE | TIMER_PERIODIC);
+                if self.control & TIMER_ENABLE != 0 {
+                    self.counter = self.reload;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> Option<u8> {
+        Some(self.register(offset))
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.control & TIMER_ENABLE == 0 {
+                return;
+            }
+
+            self.counter = self.counter.saturating_sub(1);
+            if self.counter == 0 {
+                self.interrupts.raise(self.line);
+                if self.control & TIMER_PERIODIC != 0 {
+                    self.counter = self.reload;
+                } else {
+                    self.control &= !TIMER_ENABLE;
+                }
+            }
+        }
+    }
+
+    // The five register bytes. The IRQ line is wiring and stays as constructed.
+    fn save_state(&self) -> Vec<u8> {
+        (0..5).map(|offset| self.register(offset)).collect()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader::new(state);
+        self.reload = reader.u16()?;
+        self.counter = reader.u16()?;
+        self.control = reader.u8()?;
+        reader.finish()
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::{AddressingMode, Instruction, Opcode};
+
+/// How many cycles each instruction takes.
+///
+/// An instruction costs its opcode's base cost plus the cost of its addressing
+/// mode, with extra cycles for the wide form and for conditional branches that
+/// are taken. Opcodes without an entry cost one cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleCosts {
+    opcodes: HashMap<Opcode, u32>,
+    register: u32,
+    immediate: u32,
+    indirect: u32,
+    memory: u32,
+    wide: u32,
+    taken_branch: u32,
+    interrupt: u32,
+}
+
+impl Default for CycleCosts {
+    // Roughly one cycle per bus access, with multiply and divide as slow loops.
+    fn default() -> Self {
+        let opcodes = [
+            (Opcode::MUL, 4),
+            (Opcode::MULX, 4),
+            (Opcode::IMUL, 4),
+            (Opcode::DIV, 8),
+            (Opcode::IDIV, 8),
+            (Opcode::MOD, 8),
+            (Opcode::IMOD, 8),
+            (Opcode::SWAP, 2),
+            (Opcode::CALL, 3),
+            (Opcode::RET, 3),
+            (Opcode::PUSH, 2),
+            (Opcode::POP, 2),
+            (Opcode::IRET, 5),
+        ];
+
+        CycleCosts {
+            opcodes: opcodes.into_iter().collect(),
+            register: 0,
+            immediate: 1,
+            indirect: 1,
+            memory: 2,
+            wide: 1,
+            taken_branch: 1,
+            interrupt: 6,
+        }
+    }
+}
+
+impl CycleCosts {
+    pub fn new() -> Self {
+        CycleCosts::default()
+    }
+
+    pub fn with_opcode(mut self, opcode: Opcode, cycles: u32) -> Self {
+        self.opcodes.insert(opcode, cycles);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: AddressingMode, cycles: u32) -> Self {
+        *self.mode_mut(mode) = cycles;
+        self
+    }
+
+    /// Extra cycles for instructions in the wide form.
+    pub fn with_wide(mut self, cycles: u32) -> Self {
+        self.wide = cycles;
+        self
+    }
+
+    /// Extra cycles for a conditional jump that is taken.
+    pub fn with_taken_branch(mut self, cycles: u32) -> Self {
+        self.taken_branch = cycles;
+        self
+    }
+
+    /// Cycles spent entering an interrupt handler, before its first instruction.
+    pub fn with_interrupt(mut self, cycles: u32) -> Self {
+        self.interrupt = cycles;
+        self
+    }
+
+    pub fn opcode(&self, opcode: Opcode) -> u32 {
+        self.opcodes.get(&opcode).copied().unwrap_or(1)
+    }
+
+    pub fn mode(&self, mode: AddressingMode) -> u32 {
+        match mode {
+            AddressingMode::Register => self.register,
+            AddressingMode::Immediate => self.immediate,
+            AddressingMode::Indirect => self.indirect,
+            AddressingMode::Memory => self.memory,
+        }
+    }
+
+    pub fn interrupt(&self) -> u32 {
+        self.interrupt
+    }
+
+    /// Cycles taken by `instruction`, where `taken` says whether a branch jumped.
+    pub fn instruction(&self, instruction: &Instruction, taken: bool) -> u32 {
+        let mut cycles = self.opcode(instruction.opcode()) + self.mode(instruction.mode());
+        if instruction.is_wide() {
+            cycles += self.wide;
+        }
+        if taken {
+            cycles += self.taken_branch;
+        }
+        cycles
+    }
+
+    fn mode_mut(&mut self, mode: AddressingMode) -> &mut u32 {
+        match mode {
+            AddressingMode::Register => &mut self.register,
+            AddressingMode::Immediate => &mut self.immediate,
+            AddressingMode::Indirect => &mut self.indirect,
+            AddressingMode::Memory => &mut self.memory,
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Per-instruction execution tracing.
+//!
+//! A [`Tracer`] installed with [`Cpu::set_tracer`](crate::Cpu::set_tracer)
+//! receives the [`StepOutcome`] of every instruction the CPU executes.
+//! [`WriteTracer`] renders outcomes to any writer in one of the
+//! [`TraceFormat`]s, and [`SilentTracer`] discards them.
+
+use std::io::{self, Write};
+
+use crate::{Flags, RegId, StepOutcome};
+
+// Flag letters in `Flags::bits` order.
+const FLAG_NAMES: [char; 5] = ['Z', 'N', 'C', 'V', 'I'];
+
+pub trait Tracer {
+    fn trace(&mut self, outcome: &StepOutcome);
+
+    /// Flushes buffered output and reports any error hit while tracing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A tracer that ignores every step.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SilentTracer;
+
+impl Tracer for SilentTracer {
+    fn trace(&mut self, _outcome: &StepOutcome) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One human-readable line per step: address, bytes, instruction and effects.
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Compact little-endian records:
+    ///
+    /// ```text
+    /// u16 address, u8 n, n instruction bytes
+    /// u8 interrupt line, 0xFF for none
+    /// u8 flags before, u8 flags after (as Flags::bits)
+    /// u8 n, n x (u8 register, u16 new value)   registers in RegId::ALL order
+    /// u8 n, n x (u16 address, u8 value)        reads
+    /// u8 n, n x (u16 address, u8 new value)    writes
+    /// u8 halted
+    /// ```
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" => Some(TraceFormat::Json),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+
+    /// Renders one step, including the trailing newline for the line formats.
+    pub fn encode(&self, outcome: &StepOutcome) -> Vec<u8> {
+        match self {
+            TraceFormat::Text => format!("{}\n", text(outcome)).into_bytes(),
+            TraceFormat::Json => format!("{}\n", json(outcome)).into_bytes(),
+            TraceFormat::Binary => binary(outcome),
+        }
+    }
+}
+
+/// Writes each step to `writer` in `format`.
+///
+/// The first write error stops tracing and is returned by [`Tracer::flush`].
+pub struct WriteTracer<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> WriteTracer<W> {
+    pub fn new(writer: W, format: TraceFormat) -> Self {
+        WriteTracer { writer, format, error: None }
+    }
+}
+
+impl<W: Write> Tracer for WriteTracer<W> {
+    fn trace(&mut self, outcome: &StepOutcome) {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_all(&self.format.encode(outcome)) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+fn changed_flags(before: Flags, after: Flags) -> impl Iterator<Item = (char, bool)> {
+    let (before, after) = (before.bits(), after.bits());
+    FLAG_NAMES
+        .iter()
+        .enumerate()
+        .filter(move |(bit, _)| (before ^ after) & (1 << bit) != 0)
+        .map(move |(bit, name)| (*name, after & (1 << bit) != 0))
+}
+
+// `pc` is left out of the effects since every line starts with the address.
+fn text(outcome: &StepOutcome) -> String {
+    let bytes: Vec<String> = outcome.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    let mut effects = Vec::new();
+    if let Some(line) = outcome.interrupt {
+        effects.push(format!("irq={}", line));
+    }
+    for change in outcome.registers.iter().filter(|change| change.register != RegId::PC) {
+        effects.push(format!("{}={:04X}", change.register.name(), change.new));
+    }
+    for (name, set) in changed_flags(outcome.flags_before, outcome.flags_after) {
+        effects.push(format!("{}={}", name, set as u8));
+    }
+    for read in &outcome.reads {
+        effects.push(format!("[{:04X}]->{:02X}", read.address, read.value));
+    }
+    for write in &outcome.writes {
+        effects.push(format!("[{:04X}]<-{:02X}", write.address, write.new));
+    }
+    if outcome.halted {
+        effects.push("halted".to_string());
+    }
+
+    let line = format!(
+        "{:04X}: {:<12}{:<20}{}",
+        outcome.address,
+        bytes.join(" "),
+        outcome.instruction.to_string(),
+        effects.join(" ")
+    );
+    line.trim_end().to_string()
+}
+
+fn json(outcome: &StepOutcome) -> String {
+    let list = |items: Vec<String>| format!("[{}]", items.join(","));
+
+    let bytes = list(outcome.bytes.iter().map(u8::to_string).collect());
+    let registers: Vec<String> = outcome
+        .registers
+        .iter()
+        .map(|change| format!("\"{}\":{}", change.register.name(), change.new))
+        .collect();
+    let reads = list(
+        outcome.reads.iter().map(|read| format!("{{\"address\":{},\"value\":{}}}", read.address, read.value)).collect(),
+    );
+    let writes = list(
+        outcome
+            .writes
+            .iter()
+            .map(|write| {
+                let old = write.old.map_or("null".to_string(), |old| old.to_string());
+                format!("{{\"address\":{},\"old\":{},\"new\":{}}}", write.address, old, write.new)
+            })
+            .collect(),
+    );
+    let interrupt = outcome.interrupt.map_or("null".to_string(), |line| line.to_string());
+
+    // Instruction text never holds quotes or backslashes, so it needs no escaping.
+    format!(
+        "{{\"address\":{},\"bytes\":{},\"instruction\":\"{}\",\"interrupt\":{},\"registers\":{{{}}},\
+         \"flags\":{{\"before\":{},\"after\":{}}},\"reads\":{},\"writes\":{},\"halted\":{}}}",
+        outcome.address,
+        bytes,
+        outcome.instruction,
+        interrupt,
+        registers.join(","),
+        outcome.flags_before.bits(),
+        outcome.flags_after.bits(),
+        reads,
+        writes,
+        outcome.halted
+    )
+}
+
+fn binary(outcome: &StepOutcome) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(&outcome.address.to_le_bytes());
+    record.push(outcome.bytes.len() as u8);
+    record.extend_from_slice(&outcome.bytes);
+    record.push(outcome.interrupt.unwrap_or(0xFF));
+    record.push(outcome.flags_before.bits());
+    record.push(outcome.flags_after.bits());
+
+    record.push(outcome.registers.len() as u8);
+    for change in &outcome.registers {
+        record.push(change.register as u8);
+        record.extend_from_slice(&change.new.to_le_bytes());
+    }
+
+    record.push(outcome.reads.len() as u8);
+    for read in &outcome.reads {
+        record.extend_from_slice(&read.address.to_le_bytes());
+        record.push(read.value);
+    }
+
+    record.push(outcome.writes.len() as u8);
+    for write in &outcome.writes {
+        record.extend_from_slice(&write.address.to_le_bytes());
+        record.push(write.new);
+    }
+
+    record.push(outcome.halted as u8);
+    record
+}
@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+
+use crate::snapshot::{write_chunk, Reader};
+use crate::{Device, SnapshotError};
+
+/// Offset of the data register: writes transmit a byte, reads take the next received one.
+pub const UART_DATA: u16 = 0;
+/// Offset of the read-only status register.
+pub const UART_STATUS: u16 = 1;
+/// Status bit set while received input is waiting in the data register.
+pub const UART_RX_READY: u8 = 0b01;
+/// Status bit set while the data register accepts a byte to transmit.
+pub const UART_TX_READY: u8 = 0b10;
+
+/// A serial console mapped as two byte registers, [`UART_DATA`] and [`UART_STATUS`].
+///
+/// Clones share the same queues, so one handle can be mapped onto the bus while
+/// another feeds input and collects output.
+#[derive(Clone, Default)]
+pub struct Uart {
+    state: Rc<RefCell<UartState>>,
+}
+
+#[derive(Default)]
+struct UartState {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    sink: Option<Box<dyn Write>>,
+    source: Option<Receiver<Vec<u8>>>,
+}
+
+impl Uart {
+    /// A UART collecting transmitted bytes in an output buffer.
+    pub fn new() -> Self {
+        Uart::default()
+    }
+
+    /// A UART passing transmitted bytes straight to `sink` instead of buffering them.
+    pub fn with_sink(sink: impl Write + 'static) -> Self {
+        let uart = Uart::new();
+        uart.state.borrow_mut().sink = Some(Box::new(sink));
+        uart
+    }
+
+    /// Also receives input from `source`, queued behind any pushed bytes as it
+    /// arrives, so input can come from another thread while the CPU runs.
+    pub fn with_source(self, source: Receiver<Vec<u8>>) -> Self {
+        self.state.borrow_mut().source = Some(source);
+        self
+    }
+
+    /// Queues `bytes` to be read from the data register.
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.state.borrow_mut().input.extend(bytes);
+    }
+
+    /// Bytes transmitted so far that have not been taken.
+    pub fn output(&self) -> Vec<u8> {
+        self.state.borrow().output.clone()
+    }
+
+    /// Empties and returns the output buffer.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.borrow_mut().output)
+    }
+
+    // Moves whatever the source has sent so far into the input queue.
+    fn receive(state: &mut UartState) {
+        if let Some(source) = &state.source {
+            while let Ok(bytes) = source.try_recv() {
+                state.input.extend(bytes);
+            }
+        }
+    }
+
+    fn status(state: &UartState) -> u8 {
+        if state.input.is_empty() {
+            UART_TX_READY
+        } else {
+            UART_TX_READY | UART_RX_READY
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u16) -> u8 {
+        let mut state = self.state.borrow_mut();
+        Uart::receive(&mut state);
+        match offset {
+            UART_DATA => state.input.pop_front().unwrap_or(0),
+            UART_STATUS => Uart::status(&state),
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        let mut state = self.state.borrow_mut();
+        if offset != UART_DATA {
+            return;
+        }
+        // The console has nowhere to report a failing sink, so the byte is dropped.
+        match &mut state.sink {
+            Some(sink) => {
+                let _ = sink.write_all(&[value]).and_then(|_| sink.flush());
+            }
+            None => state.output.push(value)
+        }
+    }
+
+    // Pending input then buffered output; the sink and source are part of the board, not its state.
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.state.borrow_mut();
+        Uart::receive(&mut state);
+        let mut saved = Vec::new();
+        write_chunk(&mut saved, &state.input.iter().copied().collect::<Vec<u8>>());
+        write_chunk(&mut saved, &state.output);
+        saved
+    }
+
+    fn restore_state(&mut self, saved: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader::new(saved);
+        let input = reader.chunk()?;
+        let output = reader.chunk()?;
+        reader.finish()?;
+
+        let mut state = self.state.borrow_mut();
+        state.input = input.iter().copied().collect();
+        state.output = output.to_vec();
+        Ok(())
+    }
+
+    fn peek(&self, offset: u16) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        Uart::receive(&mut state);
+        match offset {
+            UART_DATA => Some(state.input.front().copied().unwrap_or(0)),
+            UART_STATUS => Some(Uart::status(&state)),
+            _ => Some(0)
+        }
+    }
+}